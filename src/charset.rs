@@ -0,0 +1,150 @@
+use {Error, encoding, std};
+use encoding::{DecoderTrap, Encoding, EncodingRef};
+use encoding::label::encoding_from_whatwg_label;
+
+const XML_DECLARATION_LIMIT: usize = 1024;
+
+/// Transcodes a feed body to UTF-8.
+///
+/// The encoding is, in order of precedence: the override, if any; whatever a
+/// byte-order mark says; the `charset` parameter of the `Content-Type` header;
+/// the XML declaration's `encoding` attribute; and lastly UTF-8. The XML
+/// declaration, if any, is rewritten to say UTF-8 so that the XML parser
+/// doesn't decode the body a second time.
+pub fn decode(body: &[u8], content_type: Option<&str>, override_label: Option<&str>) -> Result<String, Error> {
+
+    let (encoding, body) = match override_label {
+        Some(label) => (lookup(label)?, strip_bom(body).1),
+        None => {
+            let (bom_encoding, body) = strip_bom(body);
+            let encoding = match bom_encoding {
+                Some(x) => x,
+                None => {
+                    let label = content_type.and_then(charset_param).or_else(|| xml_declared_encoding(body));
+                    match label {
+                        None => encoding::all::UTF_8 as EncodingRef,
+                        Some(label) => lookup(&label)?,
+                    }
+                }
+            };
+            (encoding, body)
+        }
+    };
+
+    let text = encoding.decode(body, DecoderTrap::Replace).map_err(|e| {
+        Error::new(format!(
+            "Failed to decode feed body as {} ({})",
+            encoding.name(),
+            e
+        )).into_error()
+    })?;
+
+    Ok(declare_utf8(text))
+}
+
+fn declare_utf8(text: String) -> String {
+    match xml_encoding_range(text.as_bytes()) {
+        Some(ref range) if !text[range.clone()].eq_ignore_ascii_case("utf-8") => {
+            format!("{}UTF-8{}", &text[..range.start], &text[range.end..])
+        }
+        _ => text,
+    }
+}
+
+fn lookup(label: &str) -> Result<EncodingRef, Error> {
+    encoding_from_whatwg_label(label.trim()).ok_or_else(|| {
+        Error::new(format!("Unknown character encoding {:?}", label)).into_error()
+    })
+}
+
+fn strip_bom(body: &[u8]) -> (Option<EncodingRef>, &[u8]) {
+    if body.starts_with(b"\xef\xbb\xbf") {
+        (Some(encoding::all::UTF_8 as EncodingRef), &body[3..])
+    } else if body.starts_with(b"\xfe\xff") {
+        (Some(encoding::all::UTF_16BE as EncodingRef), &body[2..])
+    } else if body.starts_with(b"\xff\xfe") {
+        (Some(encoding::all::UTF_16LE as EncodingRef), &body[2..])
+    } else {
+        (None, body)
+    }
+}
+
+/// Extracts the `charset` parameter from a `Content-Type` header value.
+fn charset_param(content_type: &str) -> Option<String> {
+    content_type
+        .split(';')
+        .skip(1)
+        .filter_map(|param| {
+            let mut parts = param.splitn(2, '=');
+            let name = parts.next().unwrap().trim();
+            let value = parts.next()?.trim().trim_matches('"');
+            if name.eq_ignore_ascii_case("charset") && !value.is_empty() {
+                Some(String::from(value))
+            } else {
+                None
+            }
+        })
+        .next()
+}
+
+/// Extracts the `encoding` attribute from an XML declaration at the start of
+/// the body.
+fn xml_declared_encoding(body: &[u8]) -> Option<String> {
+    xml_encoding_range(body).map(|x| String::from_utf8_lossy(&body[x]).into_owned())
+}
+
+/// Returns where the value of the `encoding` attribute is in an XML
+/// declaration at the start of the body.
+fn xml_encoding_range(body: &[u8]) -> Option<std::ops::Range<usize>> {
+
+    let head = &body[..std::cmp::min(body.len(), XML_DECLARATION_LIMIT)];
+    if !head.starts_with(b"<?xml") {
+        return None;
+    }
+
+    let end = head.iter().position(|&b| b == b'>')?;
+    let declaration = &head[..end];
+    let mut pos = declaration.windows(8).position(|x| x == b"encoding")? + "encoding".len();
+    while pos < declaration.len() && (declaration[pos] == b'=' || (declaration[pos] as char).is_whitespace()) {
+        pos += 1;
+    }
+
+    let quote = *declaration.get(pos)?;
+    if quote != b'"' && quote != b'\'' {
+        return None;
+    }
+    let start = pos + 1;
+    let len = declaration[start..].iter().position(|&b| b == quote)?;
+    Some(start..start + len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding_is_detected_in_order_of_precedence() {
+
+        let latin1 = b"<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?><rss>caf\xe9</rss>";
+        assert_eq!(
+            decode(latin1, None, None).unwrap(),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><rss>caf\u{e9}</rss>"
+        );
+        assert!(
+            decode(latin1, Some("text/xml; charset=\"windows-1252\""), None)
+                .unwrap()
+                .ends_with("<rss>caf\u{e9}</rss>")
+        );
+
+        let koi8 = b"<rss>\xf0\xd2\xc9\xd7\xc5\xd4</rss>";
+        assert_eq!(
+            decode(koi8, Some("application/rss+xml; charset=utf-8"), Some("koi8-r")).unwrap(),
+            "<rss>\u{41f}\u{440}\u{438}\u{432}\u{435}\u{442}</rss>"
+        );
+
+        let bom = b"\xef\xbb\xbf<rss>caf\xc3\xa9</rss>";
+        assert_eq!(decode(bom, Some("text/xml; charset=iso-8859-1"), None).unwrap(), "<rss>caf\u{e9}</rss>");
+
+        decode(b"<rss/>", None, Some("no-such-encoding")).unwrap_err();
+    }
+}
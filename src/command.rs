@@ -0,0 +1,209 @@
+use {Error, std};
+#[cfg(unix)]
+use libc;
+use std::io::Read;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+const STDERR_LIMIT: u64 = 4096;
+const POLL_INTERVAL_MILLIS: u64 = 50;
+
+/// Runs a command line through the shell and returns what it prints to
+/// standard output.
+///
+/// The command fails if it runs longer than the timeout, if it prints more
+/// than `max_size` bytes, or if it exits with a non-zero status, in which case
+/// the error includes what it printed to standard error.
+///
+/// On Unix, the command runs in a process group of its own, and on timeout the
+/// whole group is killed, including any processes the command started. So is
+/// anything the command leaves running once it exits. On Windows, only the
+/// shell is killed, but the command's output is awaited no longer than the
+/// timeout regardless.
+pub fn run(command_line: &str, timeout: Duration, max_size: u64) -> Result<Vec<u8>, Error> {
+
+    let mut child = shell_command(command_line)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            Error::new(format!("Failed to run command {:?}", command_line))
+                .with_cause(e)
+                .into_error()
+        })?;
+
+    // Read both pipes as the command runs, lest it block on a full pipe.
+
+    let stdout = read_in_background(child.stdout.take().unwrap(), max_size.saturating_add(1));
+    let stderr = read_in_background(child.stderr.take().unwrap(), STDERR_LIMIT);
+
+    let timed_out = || {
+        Error::new(format!(
+            "Command timed out after {} seconds (command: {:?})",
+            timeout.as_secs(),
+            command_line
+        )).into_error()
+    };
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Err(e) => {
+                return Err(
+                    Error::new(format!("Failed to wait for command {:?}", command_line))
+                        .with_cause(e)
+                        .into_error(),
+                );
+            }
+            Ok(Some(x)) => break x,
+            Ok(None) => {}
+        }
+        if deadline <= Instant::now() {
+            kill(&mut child);
+            let _ = child.wait();
+            return Err(timed_out());
+        }
+        std::thread::sleep(Duration::from_millis(POLL_INTERVAL_MILLIS));
+    };
+
+    // Anything the command left running in the background may hold the pipes
+    // open, and then they would never reach end-of-file.
+
+    kill(&mut child);
+    let stdout = receive_by(&stdout, deadline).ok_or_else(&timed_out)?;
+    let stderr = receive_by(&stderr, deadline).ok_or_else(&timed_out)?;
+
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr);
+        let stderr = stderr.trim();
+        return Err(
+            Error::new(format!(
+                "Command failed with {} (command: {:?}){}{}",
+                status,
+                command_line,
+                if stderr.is_empty() { "" } else { ": " },
+                stderr
+            )).into_error(),
+        );
+    }
+
+    if max_size < stdout.len() as u64 {
+        return Err(
+            Error::new(format!(
+                "Command output exceeds the maximum size of {} bytes (command: {:?})",
+                max_size,
+                command_line
+            )).into_error(),
+        );
+    }
+
+    Ok(stdout)
+}
+
+#[cfg(unix)]
+fn shell_command(command_line: &str) -> Command {
+
+    use std::os::unix::process::CommandExt;
+
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(command_line);
+
+    // setpgid is async-signal-safe, so it may run between fork and exec.
+
+    unsafe {
+        command.before_exec(|| if libc::setpgid(0, 0) == 0 {
+            Ok(())
+        } else {
+            Err(std::io::Error::last_os_error())
+        });
+    }
+
+    command
+}
+
+#[cfg(windows)]
+fn shell_command(command_line: &str) -> Command {
+    let mut command = Command::new("cmd");
+    command.arg("/C").arg(command_line);
+    command
+}
+
+/// Kills the command's process group, whose ID is the shell's process ID,
+/// whether or not the shell itself has exited.
+#[cfg(unix)]
+fn kill(child: &mut Child) {
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(windows)]
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+}
+
+/// Reads up to `limit` bytes in a separate thread, then drains the rest so
+/// that the writer never blocks. What's read arrives through the channel at
+/// end-of-file.
+fn read_in_background<R: Read + Send + 'static>(mut reader: R, limit: u64) -> Receiver<Vec<u8>> {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = (&mut reader).take(limit).read_to_end(&mut buf);
+        let _ = std::io::copy(&mut reader, &mut std::io::sink());
+        let _ = sender.send(buf);
+    });
+    receiver
+}
+
+/// Waits for what a background reader read until the deadline, though always
+/// briefly, lest a command that exits just in time fail for want of a moment.
+fn receive_by(receiver: &Receiver<Vec<u8>>, deadline: Instant) -> Option<Vec<u8>> {
+    let now = Instant::now();
+    let grace = Duration::from_millis(POLL_INTERVAL_MILLIS);
+    let wait = if now + grace < deadline { deadline - now } else { grace };
+    receiver.recv_timeout(wait).ok()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn commands_fail_on_error_status_or_timeout() {
+
+        let timeout = Duration::from_secs(10);
+        assert_eq!(run("printf '<rss/>'", timeout, 1024).unwrap(), b"<rss/>");
+
+        let e = run("echo oops >&2; exit 3", timeout, 1024).unwrap_err();
+        assert!(e.to_string().contains("oops"));
+
+        run("printf '<rss/>'", timeout, 3).unwrap_err();
+        run("sleep 10", Duration::from_millis(100), 1024).unwrap_err();
+    }
+
+    #[test]
+    fn timing_out_kills_what_the_command_started() {
+
+        let tdir = TempDir::new("rss2email").unwrap();
+        let marker_path = tdir.path().join("marker");
+
+        let command_line = format!("(sleep 1; touch '{}') & wait", marker_path.to_str().unwrap());
+        run(&command_line, Duration::from_millis(100), 1024).unwrap_err();
+
+        std::thread::sleep(Duration::from_millis(1500));
+        assert!(!marker_path.exists());
+    }
+
+    #[test]
+    fn background_processes_dont_hold_up_a_finished_command() {
+
+        let start = Instant::now();
+        let output = run("sleep 30 & printf '<rss/>'", Duration::from_secs(5), 1024).unwrap();
+        assert_eq!(output, b"<rss/>");
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
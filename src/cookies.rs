@@ -0,0 +1,306 @@
+use {Error, reqwest, std};
+use chrono::{DateTime, Duration, NaiveDateTime, TimeZone, Utc};
+use reqwest::Url;
+
+/// `MAX_DURATION_SECS` is the longest duration, in seconds, that `Duration`
+/// can hold.
+const MAX_DURATION_SECS: i64 = i64::max_value() / 1000;
+
+/// `CookieJar` holds the cookies for one feed, kept in the database so that
+/// they persist from one run to the next.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct CookieJar {
+    cookies: Vec<Cookie>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    domain: String,
+    include_subdomains: bool,
+    path: String,
+    secure: bool,
+    expires: Option<DateTime<Utc>>, // none for a session cookie
+}
+
+impl CookieJar {
+    pub fn new() -> Self {
+        CookieJar::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cookies.len()
+    }
+
+    /// Parses cookies in the Netscape `cookies.txt` format, as exported by
+    /// browsers and by `curl`.
+    pub fn parse_netscape(text: &str) -> Result<Self, Error> {
+
+        let mut jar = CookieJar::new();
+
+        for (n, line) in text.lines().enumerate() {
+
+            const HTTP_ONLY_PREFIX: &str = "#HttpOnly_";
+
+            let line = line.trim_right_matches('\r');
+            let line = if line.starts_with(HTTP_ONLY_PREFIX) {
+                &line[HTTP_ONLY_PREFIX.len()..]
+            } else if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            } else {
+                line
+            };
+
+            let fields = line.split('\t').collect::<Vec<_>>();
+            if fields.len() != 7 {
+                return Err(
+                    Error::new(format!(
+                        "Invalid cookies.txt line {} (expected 7 tab-separated fields, got {})",
+                        n + 1,
+                        fields.len()
+                    )).into_error(),
+                );
+            }
+
+            let expires = fields[4].parse::<i64>().map_err(|e| {
+                Error::new(format!("Invalid cookies.txt line {} (bad expiry time)", n + 1))
+                    .with_cause(e)
+                    .into_error()
+            })?;
+
+            jar.insert(Cookie {
+                name: String::from(fields[5]),
+                value: String::from(fields[6]),
+                domain: fields[0].trim_left_matches('.').to_lowercase(),
+                include_subdomains: fields[1].eq_ignore_ascii_case("TRUE"),
+                path: String::from(fields[2]),
+                secure: fields[3].eq_ignore_ascii_case("TRUE"),
+                expires: if expires == 0 {
+                    None
+                } else {
+                    Utc.timestamp_opt(expires, 0).single() // too far off to represent means never
+                },
+            });
+        }
+
+        Ok(jar)
+    }
+
+    /// Adds the cookies from another jar, replacing any of the same name,
+    /// domain and path.
+    pub fn merge(&mut self, other: CookieJar) {
+        for cookie in other.cookies {
+            self.insert(cookie);
+        }
+    }
+
+    /// Returns the value of the `Cookie` header for a request for the URL, if
+    /// any cookies apply.
+    pub fn header_for(&self, url: &Url, now: DateTime<Utc>) -> Option<String> {
+
+        let host = url.host_str().unwrap_or("").to_lowercase();
+        let path = url.path();
+
+        let pairs = self.cookies
+            .iter()
+            .filter(|c| c.expires.map(|x| now < x).unwrap_or(true))
+            .filter(|c| !c.secure || url.scheme() == "https")
+            .filter(|c| {
+                host == c.domain || (c.include_subdomains && host.ends_with(&format!(".{}", c.domain)))
+            })
+            .filter(|c| {
+                path == c.path ||
+                    (path.starts_with(&c.path) &&
+                         (c.path.ends_with('/') || path[c.path.len()..].starts_with('/')))
+            })
+            .map(|c| format!("{}={}", c.name, c.value))
+            .collect::<Vec<_>>();
+
+        if pairs.is_empty() {
+            return None;
+        }
+
+        Some(pairs.join("; "))
+    }
+
+    /// Stores the cookie from a `Set-Cookie` header in a response for the URL.
+    /// A cookie that can't be parsed, or that's for another domain, is ignored.
+    pub fn store_set_cookie(&mut self, url: &Url, set_cookie: &str, now: DateTime<Utc>) {
+
+        let host = url.host_str().unwrap_or("").to_lowercase();
+        let mut attrs = set_cookie.split(';');
+
+        let (name, value) = {
+            let mut parts = attrs.next().unwrap().splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if !name.trim().is_empty() => {
+                    (String::from(name.trim()), String::from(value.trim()))
+                }
+                _ => return,
+            }
+        };
+
+        let mut cookie = Cookie {
+            name: name,
+            value: value,
+            domain: host.clone(),
+            include_subdomains: false,
+            path: default_path(url),
+            secure: false,
+            expires: None,
+        };
+
+        let mut max_age = None;
+
+        for attr in attrs {
+            let mut parts = attr.splitn(2, '=');
+            let key = parts.next().unwrap().trim().to_lowercase();
+            let value = parts.next().map(|x| x.trim()).unwrap_or("");
+            match key.as_str() {
+                "domain" if !value.is_empty() => {
+                    let domain = value.trim_left_matches('.').to_lowercase();
+                    if host != domain && !host.ends_with(&format!(".{}", domain)) {
+                        return; // a host may not set cookies for another domain
+                    }
+                    cookie.domain = domain;
+                    cookie.include_subdomains = true;
+                }
+                "path" if value.starts_with('/') => cookie.path = String::from(value),
+                "secure" => cookie.secure = true,
+                "expires" => {
+                    if let Some(x) = parse_cookie_date(value) {
+                        cookie.expires = Some(x);
+                    }
+                }
+                "max-age" => max_age = value.parse::<i64>().ok(),
+                _ => {}
+            }
+        }
+
+        // A Max-Age of zero or less expires the cookie at once. One too large
+        // for a date means that the cookie never expires.
+
+        if let Some(x) = max_age {
+            cookie.expires = if x <= 0 {
+                Some(now)
+            } else {
+                now.checked_add_signed(Duration::seconds(std::cmp::min(x, MAX_DURATION_SECS)))
+            };
+        }
+
+        self.insert(cookie);
+        self.remove_expired(now);
+    }
+
+    fn insert(&mut self, cookie: Cookie) {
+        self.cookies.retain(|x| {
+            x.name != cookie.name || x.domain != cookie.domain || x.path != cookie.path
+        });
+        self.cookies.push(cookie);
+    }
+
+    fn remove_expired(&mut self, now: DateTime<Utc>) {
+        self.cookies.retain(|x| x.expires.map(|x| now < x).unwrap_or(true));
+    }
+}
+
+/// Parses the `Expires` attribute of a cookie, which servers write in any of
+/// several formats besides the RFC 1123 format—e.g., `Wed, 21-Oct-2015
+/// 07:28:00 GMT`. The time zone is always GMT.
+fn parse_cookie_date(value: &str) -> Option<DateTime<Utc>> {
+
+    if let Ok(x) = DateTime::parse_from_rfc2822(value) {
+        return Some(x.with_timezone(&Utc));
+    }
+
+    let value = value.trim();
+    let zone = value.rfind(' ').map(|n| &value[n + 1..]).unwrap_or("");
+    let value = if zone.eq_ignore_ascii_case("GMT") || zone.eq_ignore_ascii_case("UTC") {
+        value[..value.len() - zone.len()].trim()
+    } else {
+        value
+    };
+
+    let formats = [
+        "%a, %d-%b-%Y %H:%M:%S", // Netscape
+        "%a, %d-%b-%y %H:%M:%S",
+        "%A, %d-%b-%y %H:%M:%S", // RFC 850
+        "%a, %d %b %Y %H:%M:%S",
+        "%a %b %e %H:%M:%S %Y", // asctime
+    ];
+
+    formats
+        .iter()
+        .filter_map(|x| NaiveDateTime::parse_from_str(value, x).ok())
+        .next()
+        .map(|x| Utc.from_utc_datetime(&x))
+}
+
+/// Returns the default path of a cookie set by a response for the URL, as
+/// defined by RFC 6265.
+fn default_path(url: &Url) -> String {
+    let path = url.path();
+    match path.rfind('/') {
+        None | Some(0) => String::from("/"),
+        Some(n) => String::from(&path[..n]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cookies_are_sent_only_where_they_apply() {
+
+        let now = Utc::now();
+        let text = "# Netscape HTTP Cookie File\n\
+                    .example.com\tTRUE\t/\tFALSE\t0\tsession\talpha\n\
+                    #HttpOnly_feeds.example.com\tFALSE\t/private\tTRUE\t0\ttoken\tbravo\n\
+                    example.com\tFALSE\t/\tFALSE\t1\tstale\tcharlie\n";
+        let mut jar = CookieJar::parse_netscape(text).unwrap();
+        assert_eq!(jar.len(), 3);
+
+        let header_for = |jar: &CookieJar, url: &str| jar.header_for(&Url::parse(url).unwrap(), now);
+        assert_eq!(header_for(&jar, "http://feeds.example.com/rss"), Some(String::from("session=alpha")));
+        assert_eq!(
+            header_for(&jar, "https://feeds.example.com/private/rss"),
+            Some(String::from("session=alpha; token=bravo"))
+        );
+        assert_eq!(header_for(&jar, "http://feeds.example.com/private/rss"), Some(String::from("session=alpha")));
+        assert_eq!(header_for(&jar, "http://example.org/rss"), None);
+
+        let url = Url::parse("http://feeds.example.com/private/rss").unwrap();
+        jar.store_set_cookie(&url, "session=delta; Domain=example.com; Path=/", now);
+        jar.store_set_cookie(&url, "evil=echo; Domain=example.org", now);
+        assert_eq!(jar.len(), 2); // the stale cookie is gone too
+        assert_eq!(header_for(&jar, "http://example.com/"), Some(String::from("session=delta")));
+    }
+
+    #[test]
+    fn cookie_expiry_is_parsed_leniently() {
+
+        let now = Utc.ymd(2015, 10, 1).and_hms(0, 0, 0);
+        let url = Url::parse("http://example.com/").unwrap();
+        let expires = |set_cookie: &str| {
+            let mut jar = CookieJar::new();
+            jar.store_set_cookie(&url, set_cookie, now);
+            jar.cookies.first().map(|x| x.expires)
+        };
+
+        let expected = Some(Some(Utc.ymd(2015, 10, 21).and_hms(7, 28, 0)));
+        assert_eq!(expires("a=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT"), expected);
+        assert_eq!(expires("a=1; Expires=Wed, 21-Oct-2015 07:28:00 GMT"), expected);
+        assert_eq!(expires("a=1; Expires=Wednesday, 21-Oct-15 07:28:00 GMT"), expected);
+        assert_eq!(expires("a=1; Expires=Wed Oct 21 07:28:00 2015"), expected);
+        assert_eq!(expires("a=1; Expires=whenever"), Some(None));
+
+        assert_eq!(expires("a=1; Max-Age=60"), Some(Some(now + Duration::seconds(60))));
+        assert_eq!(expires("a=1; Max-Age=99999999999999999"), Some(None));
+        assert_eq!(expires("a=1; Max-Age=-99999999999999999"), None); // expired at once
+
+        let jar = CookieJar::parse_netscape("example.com\tFALSE\t/\tFALSE\t99999999999999999\ta\t1\n").unwrap();
+        assert_eq!(jar.cookies[0].expires, None);
+    }
+}
@@ -0,0 +1,165 @@
+use std;
+use reqwest::Url;
+
+const FEED_TYPES: &[&str] = &["application/rss+xml", "application/atom+xml", "application/feed+json"];
+
+/// `FeedLink` is a feed that an HTML page links to as an alternate version of
+/// itself.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeedLink {
+    url: String,
+    title: Option<String>,
+    mime_type: String,
+}
+
+impl FeedLink {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_ref().map(|x| x.as_str())
+    }
+
+    pub fn mime_type(&self) -> &str {
+        &self.mime_type
+    }
+}
+
+/// Finds the `<link rel="alternate">` elements in an HTML page that name a
+/// feed, resolving their URLs against the page's URL.
+pub fn feed_links(page_url: &Url, html: &str) -> Vec<FeedLink> {
+
+    let lower = html.to_ascii_lowercase(); // the same length, so indexes match
+    let mut links = Vec::new();
+    let mut pos = 0;
+
+    while let Some(n) = lower[pos..].find("<link") {
+
+        let start = pos + n + "<link".len();
+        let end = match lower[start..].find('>') {
+            None => break,
+            Some(x) => start + x,
+        };
+        pos = end;
+
+        let attrs = parse_attributes(&html[start..end]);
+        let attr = |name: &str| {
+            attrs
+                .iter()
+                .find(|&&(ref x, _)| x == name)
+                .map(|&(_, ref value)| value.as_str())
+        };
+
+        let is_alternate = attr("rel")
+            .map(|x| x.split_whitespace().any(|x| x.eq_ignore_ascii_case("alternate")))
+            .unwrap_or(false);
+        let mime_type = attr("type").map(|x| x.trim().to_lowercase()).unwrap_or_default();
+        if !is_alternate || !FEED_TYPES.contains(&mime_type.as_str()) {
+            continue;
+        }
+
+        let url = match attr("href").and_then(|x| page_url.join(x.trim()).ok()) {
+            None => continue,
+            Some(x) => x,
+        };
+
+        let title = attr("title").map(|x| x.trim()).and_then(|x| {
+            if x.is_empty() { None } else { Some(String::from(x)) }
+        });
+
+        links.push(FeedLink {
+            url: String::from(url.as_str()),
+            title: title,
+            mime_type: mime_type,
+        });
+    }
+
+    links
+}
+
+/// Parses the attributes of an HTML tag, lowercasing their names and decoding
+/// the common character references in their values.
+fn parse_attributes(tag: &str) -> Vec<(String, String)> {
+
+    let mut attrs = Vec::new();
+    let mut rest = tag;
+
+    loop {
+        rest = rest.trim_left();
+        let name_len = rest.find(|c: char| c == '=' || c.is_whitespace()).unwrap_or(rest.len());
+        if name_len == 0 {
+            break;
+        }
+        let name = rest[..name_len].to_lowercase();
+        rest = rest[name_len..].trim_left();
+
+        if !rest.starts_with('=') {
+            attrs.push((name, String::new()));
+            continue;
+        }
+        rest = rest[1..].trim_left();
+
+        let value = match rest.chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => {
+                let len = rest[1..].find(quote).unwrap_or(rest.len() - 1);
+                let value = &rest[1..1 + len];
+                rest = &rest[std::cmp::min(2 + len, rest.len())..];
+                value
+            }
+            _ => {
+                let len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                let value = &rest[..len];
+                rest = &rest[len..];
+                value
+            }
+        };
+
+        attrs.push((name, decode_references(value)));
+    }
+
+    attrs
+}
+
+fn decode_references(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alternate_feed_links_are_found() {
+
+        let html = "<!DOCTYPE html><html><head>\
+                    <link rel=\"stylesheet\" href=\"/style.css\">\
+                    <LINK REL=\"alternate\" TYPE=\"application/rss+xml\" \
+                    TITLE=\"Posts &amp; news\" HREF=\"/rss.xml\">\
+                    <link rel='alternate' type='application/atom+xml' \
+                    href='https://feeds.example.com/atom?a=1&amp;b=2' />\
+                    <link rel=alternate type=text/html href=/fr/>\
+                    <link rel=\"alternate\" type=\"application/feed+json\" href=\"feed.json\">\
+                    </head></html>";
+        let page_url = Url::parse("http://example.com/blog/").unwrap();
+
+        let links = feed_links(&page_url, html);
+        assert_eq!(
+            links.iter().map(|x| x.url()).collect::<Vec<_>>(),
+            vec![
+                "http://example.com/rss.xml",
+                "https://feeds.example.com/atom?a=1&b=2",
+                "http://example.com/blog/feed.json",
+            ]
+        );
+        assert_eq!(links[0].title(), Some("Posts & news"));
+        assert_eq!(links[1].title(), None);
+        assert_eq!(links[2].mime_type(), "application/feed+json");
+    }
+}
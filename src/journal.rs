@@ -0,0 +1,133 @@
+use {Error, serde_json, std};
+use model::FeedItem;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+/// `Journal` is an append-only log of the feed items sent since the database
+/// was last committed.
+///
+/// Each record is synced to disk as soon as it's appended, so that if the
+/// process dies before committing the database, the next open can fold the
+/// sent items back in and not send them again.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: Option<std::fs::File>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct JournalEntry {
+    pub feed_url: String,
+    pub item_id: String,
+    pub item: FeedItem,
+}
+
+impl Journal {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Journal {
+            path: PathBuf::from(path.as_ref()),
+            file: None,
+        }
+    }
+
+    /// Reads all records in the journal, if any.
+    ///
+    /// A record torn by a crash mid-append is skipped. Nothing was sent after
+    /// such a record was written, so nothing is lost.
+    pub fn replay(&self) -> Result<Vec<JournalEntry>, Error> {
+
+        let f = match std::fs::File::open(&self.path) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(
+                Error::new(format!("Failed to open journal (path: {:?})", self.path))
+                    .with_cause(e)
+                    .into_error(),
+            ),
+            Ok(x) => x,
+        };
+
+        let mut entries = Vec::new();
+
+        for line in std::io::BufReader::new(f).split(b'\n') {
+            let line = line.map_err(|e| {
+                Error::new(format!("Failed to read journal (path: {:?})", self.path))
+                    .with_cause(e)
+                    .into_error()
+            })?;
+            if let Ok(entry) = serde_json::from_slice(&line) {
+                entries.push(entry);
+            }
+        }
+
+        Ok(entries)
+    }
+
+    pub fn append(&mut self, entry: &JournalEntry) -> Result<(), Error> {
+
+        let path = &self.path;
+
+        if self.file.is_none() {
+
+            let mut f = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| {
+                    Error::new(format!("Failed to open journal (path: {:?})", path))
+                        .with_cause(e)
+                        .into_error()
+                })?;
+
+            // Terminate any record torn by an earlier crash so that it doesn't
+            // run into the records we're about to append.
+
+            f.write_all(b"\n").map_err(|e| {
+                Error::new(format!("Failed to write to journal (path: {:?})", path))
+                    .with_cause(e)
+                    .into_error()
+            })?;
+
+            self.file = Some(f);
+        }
+
+        let f = self.file.as_mut().unwrap();
+
+        let mut record = serde_json::to_vec(entry).map_err(|e| {
+            Error::new(format!("Failed to encode journal record (path: {:?})", path))
+                .with_cause(e)
+                .into_error()
+        })?;
+        record.push(b'\n');
+
+        f.write_all(&record).map_err(|e| {
+            Error::new(format!("Failed to write to journal (path: {:?})", path))
+                .with_cause(e)
+                .into_error()
+        })?;
+
+        f.sync_data().map_err(|e| {
+            Error::new(format!("Failed to sync journal to disk (path: {:?})", path))
+                .with_cause(e)
+                .into_error()
+        })?;
+
+        Ok(())
+    }
+
+    /// Discards all records, which must already be reflected in the committed
+    /// database.
+    pub fn clear(&mut self) -> Result<(), Error> {
+
+        self.file = None;
+
+        match std::fs::remove_file(&self.path) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(
+                Error::new(format!("Failed to remove journal (path: {:?})", self.path))
+                    .with_cause(e)
+                    .into_error(),
+            ),
+            Ok(..) => Ok(()),
+        }
+    }
+}
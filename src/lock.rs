@@ -0,0 +1,95 @@
+use {Error, fs2, std};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL_MILLIS: u64 = 100;
+
+/// `LockFile` is an exclusive advisory lock, held until dropped.
+///
+/// The lock file holds the PID of the process holding the lock, so that a
+/// process that fails to acquire the lock can say who has it.
+#[derive(Debug)]
+pub struct LockFile {
+    file: std::fs::File,
+}
+
+impl LockFile {
+    /// Acquires the lock, waiting up to `wait` for another process to release
+    /// it.
+    pub fn acquire<P: AsRef<Path>>(path: P, wait: Duration) -> Result<Self, Error> {
+
+        use fs2::FileExt;
+
+        let path = path.as_ref();
+
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| {
+                Error::new(format!("Failed to open lock file (path: {:?})", path))
+                    .with_cause(e)
+                    .into_error()
+            })?;
+
+        let deadline = Instant::now() + wait;
+
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(()) => break,
+                Err(ref e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                    if deadline <= Instant::now() {
+                        return Err(
+                            Error::new(format!(
+                                "Database is in use by another process (pid: {}, lock file: {:?})",
+                                read_holder(&mut file).unwrap_or(String::from("unknown")),
+                                path
+                            )).into_error(),
+                        );
+                    }
+                    std::thread::sleep(Duration::from_millis(POLL_INTERVAL_MILLIS));
+                }
+                Err(e) => return Err(
+                    Error::new(format!("Failed to lock file (path: {:?})", path))
+                        .with_cause(e)
+                        .into_error(),
+                ),
+            }
+        }
+
+        write_holder(&mut file).map_err(|e| {
+            Error::new(format!("Failed to write lock file (path: {:?})", path))
+                .with_cause(e)
+                .into_error()
+        })?;
+
+        Ok(LockFile { file: file })
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        use fs2::FileExt;
+        let _ = self.file.unlock(); // closing the file would release it anyway
+    }
+}
+
+fn write_holder(file: &mut std::fs::File) -> Result<(), std::io::Error> {
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(format!("{}\n", std::process::id()).as_bytes())?;
+    file.flush()
+}
+
+fn read_holder(file: &mut std::fs::File) -> Option<String> {
+    let mut content = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut content).ok()?;
+    let pid = content.trim();
+    if pid.is_empty() {
+        return None; // holder hasn't written its PID yet
+    }
+    Some(String::from(pid))
+}
@@ -18,7 +18,6 @@ pub enum LogLevel {
 #[derive(Clone, Copy, Debug)]
 pub enum LogKind {
     Error,
-    #[allow(unused)]
     Warning,
     Info,
 }
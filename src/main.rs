@@ -1,13 +1,20 @@
 extern crate atom_syndication;
+extern crate brotli_decompressor;
 extern crate byteorder;
 extern crate chrono;
 extern crate clap;
+extern crate encoding;
 extern crate escapade;
+extern crate flate2;
+extern crate fs2;
 extern crate futures;
+extern crate hyper;
 extern crate lettre;
+extern crate libc;
 extern crate reqwest;
 extern crate rmp_serde;
 extern crate rss;
+extern crate rusqlite;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -16,10 +23,20 @@ extern crate serde_json;
 extern crate tempdir;
 extern crate toml;
 
+mod charset;
+mod command;
 mod config;
+mod cookies;
+mod discover;
 mod error;
+mod journal;
+mod lock;
 mod log;
 mod model;
+mod proxy;
+mod schedule;
+mod storage;
+mod throttle;
 
 pub use error::Error;
 
@@ -62,13 +79,60 @@ fn main_impl() -> Result<(), Error> {
         .subcommand(
             SubCommand::with_name("add")
                 .about("Add feed to the database")
+                .arg(Arg::with_name("DISCOVER").long("discover").help(
+                    "Fetch the URL first and, if it is an HTML page, add a feed it links to",
+                ))
+                .arg(
+                    Arg::with_name("FIRST")
+                        .long("first")
+                        .requires("DISCOVER")
+                        .help("Add the first feed the page links to instead of asking which"),
+                )
                 .arg(
                     Arg::with_name("FEED_URL")
                         .help("URL of the feed to add")
                         .required(true),
                 ),
         )
+        .subcommand(SubCommand::with_name("backups").about(
+            "Print the names of all database backups, newest first",
+        ))
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Check the database for problems")
+                .arg(Arg::with_name("REPAIR").long("repair").help(
+                    "Salvage every readable feed into a fresh database",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("Convert the database file to another format")
+                .arg(
+                    Arg::with_name("FORMAT")
+                        .help("Format to convert to")
+                        .possible_values(&["json", "msgpack"])
+                        .required(true),
+                ),
+        )
         .subcommand(SubCommand::with_name("create").about("Create database"))
+        .subcommand(
+            SubCommand::with_name("disable")
+                .about("Stop fetching a feed, keeping it in the database")
+                .arg(
+                    Arg::with_name("FEED_URL")
+                        .help("URL of the feed to disable")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("enable")
+                .about("Resume fetching a disabled feed")
+                .arg(
+                    Arg::with_name("FEED_URL")
+                        .help("URL of the feed to enable")
+                        .required(true),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("fetch")
                 .about("Fetch feeds and send emails for new items")
@@ -82,11 +146,90 @@ fn main_impl() -> Result<(), Error> {
                 .arg(Arg::with_name("NO_SEND").long("no-send").help(
                     "Run as normal but do not send emails",
                 ))
+                .arg(Arg::with_name("FORCE").long("force").help(
+                    "Fetch feeds even if they are not due",
+                ))
+                .arg(
+                    Arg::with_name("STDIN")
+                        .long("stdin")
+                        .requires("FEED_URL")
+                        .help("Read the feed document from standard input instead of fetching it"),
+                )
                 .arg(Arg::with_name("FEED_URL").multiple(true).help(
                     "URL of the feed(s) to fetch",
                 )),
         )
+        .subcommand(
+            SubCommand::with_name("import-cookies")
+                .about("Add cookies from a cookies.txt file to a feed's cookie jar")
+                .arg(
+                    Arg::with_name("FEED_URL")
+                        .help("URL of the feed to send the cookies with")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("COOKIES_FILE")
+                        .help("Path of the file, in the Netscape cookies.txt format")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Print what is known about fetching a feed")
+                .arg(
+                    Arg::with_name("FEED_URL")
+                        .help("URL of the feed to describe")
+                        .required(true),
+                ),
+        )
         .subcommand(SubCommand::with_name("list").about("Print all feed URLs"))
+        .subcommand(
+            SubCommand::with_name("migrate-storage")
+                .about("Convert the database to another kind of storage")
+                .arg(
+                    Arg::with_name("STORAGE")
+                        .help("Kind of storage to convert to")
+                        .possible_values(&["json", "msgpack", "sqlite"])
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("move")
+                .about("Change a feed's URL, keeping the items already sent")
+                .arg(Arg::with_name("MERGE").long("merge").help(
+                    "Merge into the feed at the new URL if it already exists",
+                ))
+                .arg(
+                    Arg::with_name("OLD_URL")
+                        .help("Current URL of the feed")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("NEW_URL")
+                        .help("New URL of the feed")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("prune")
+                .about("Forget stale feed items according to the retention settings")
+                .arg(
+                    Arg::with_name("VERBOSE")
+                        .short("v")
+                        .long("verbose")
+                        .multiple(true)
+                        .help("Print more information"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Restore the database from a backup")
+                .arg(
+                    Arg::with_name("BACKUP")
+                        .help("Name of the backup to restore, as printed by the backups subcommand")
+                        .required(true),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("remove")
                 .about("Remove a feed from the database")
@@ -101,41 +244,234 @@ fn main_impl() -> Result<(), Error> {
 
     if let Some(matches) = matches.subcommand_matches("add") {
         let feed_url = matches.value_of("FEED_URL").unwrap();
-        let mut db = Database::open(DB_PATH)?;
-        db.add_feed(feed_url)?;
-        db.commit()?;
+        if matches.is_present("DISCOVER") {
+            let config = config::FetchOnlyConfig::load(CONFIG_PATH)?;
+            let mut db = Database::open_with_config(DB_PATH, &config.database)?;
+            let fetcher = model::NetFetcher::new(&fetcher_options(&config.fetch)?)?;
+            let logger = log::Logger::new(log::LogLevel::Normal);
+            let (feed_url, title) = match fetcher.discover(&logger, feed_url, config.feeds.get(feed_url))? {
+                model::Discovery::Feed(title) => (String::from(feed_url), title),
+                model::Discovery::Page(links) => {
+                    let link = choose_feed_link(feed_url, links, matches.is_present("FIRST"))?;
+                    match fetcher.discover(&logger, link.url(), config.feeds.get(link.url()))? {
+                        model::Discovery::Feed(title) => {
+                            (String::from(link.url()), title.or(link.title().map(String::from)))
+                        }
+                        model::Discovery::Page(_) => {
+                            return Err(
+                                Error::new(format!("Linked feed is not a feed (URL: {})", link.url())).into_error(),
+                            );
+                        }
+                    }
+                }
+            };
+            db.add_feed_with_title(&feed_url, title)?;
+            db.commit()?;
+        } else {
+            let db_config = config::DatabaseConfig::load(CONFIG_PATH)?;
+            let mut db = Database::open_with_config(DB_PATH, &db_config)?;
+            db.add_feed(feed_url)?;
+            db.commit()?;
+        }
+    } else if let Some(_matches) = matches.subcommand_matches("backups") {
+        let db_config = config::DatabaseConfig::load(CONFIG_PATH)?;
+        let db = Database::open_with_config(DB_PATH, &db_config)?;
+        let stdout = std::io::stdout();
+        let mut w = stdout.lock();
+        for name in db.backups()? {
+            writeln!(w, "{}", name).unwrap();
+        }
+    } else if let Some(matches) = matches.subcommand_matches("check") {
+        let db_config = config::DatabaseConfig::load(CONFIG_PATH)?;
+        let repair = matches.is_present("REPAIR");
+        let problems = Database::check(DB_PATH, &db_config, repair)?;
+        let stdout = std::io::stdout();
+        let mut w = stdout.lock();
+        for problem in problems.iter() {
+            writeln!(w, "{}", problem).unwrap();
+        }
+        if !problems.is_empty() && !repair {
+            return Err(
+                Error::new(format!(
+                    "Found {} problem(s) in database; run with --repair to fix them",
+                    problems.len()
+                )).into_error(),
+            );
+        }
+    } else if let Some(matches) = matches.subcommand_matches("convert") {
+        let kind = config::StorageKind::from_name(matches.value_of("FORMAT").unwrap()).unwrap();
+        let db_config = config::DatabaseConfig::load(CONFIG_PATH)?;
+        let mut db = Database::open_with_config(DB_PATH, &db_config)?;
+        db.convert_storage(kind)?;
     } else if let Some(_matches) = matches.subcommand_matches("create") {
-        let db = Database::create(DB_PATH)?;
+        let db_config = config::DatabaseConfig::load(CONFIG_PATH)?;
+        let mut db = Database::create_with_config(DB_PATH, &db_config)?;
+        db.commit()?;
+    } else if let Some(matches) = matches.subcommand_matches("disable") {
+        let feed_url = matches.value_of("FEED_URL").unwrap();
+        let db_config = config::DatabaseConfig::load(CONFIG_PATH)?;
+        let mut db = Database::open_with_config(DB_PATH, &db_config)?;
+        db.disable_feed(feed_url)?;
+        db.commit()?;
+    } else if let Some(matches) = matches.subcommand_matches("enable") {
+        let feed_url = matches.value_of("FEED_URL").unwrap();
+        let db_config = config::DatabaseConfig::load(CONFIG_PATH)?;
+        let mut db = Database::open_with_config(DB_PATH, &db_config)?;
+        db.enable_feed(feed_url)?;
         db.commit()?;
     } else if let Some(matches) = matches.subcommand_matches("fetch") {
         let config = config::Config::load(CONFIG_PATH)?;
-        let mut db = Database::open(DB_PATH)?;
+        let mut db = Database::open_with_config(DB_PATH, &config.database)?;
         let log_level = match matches.occurrences_of("VERBOSE") {
             0 => log::LogLevel::Normal,
             _ => log::LogLevel::Verbose,
         };
         let logger = Arc::new(log::Logger::new(log_level));
-        let fetcher = model::NetFetcher::new()?;
         let sender = model::EmailSender::new(&config)?;
         let mut options = model::FetchAndSendOptions::new();
         options.with_no_send(matches.is_present("NO_SEND"));
+        options.with_force(matches.is_present("FORCE") || matches.is_present("STDIN"));
+        options.with_retention(retention_policy(&config.database));
+        if let Some(runs) = config.fetch.permanent_redirect_runs {
+            options.with_permanent_redirect_runs(runs);
+        }
+        if let Some(days) = config.fetch.disable_after_days {
+            options.with_disable_after_days(days);
+        }
+        options.with_feed_configs(config.feeds.clone());
         if let Some(feed_urls) = matches.values_of("FEED_URL") {
             options.with_feed_urls(feed_urls);
         }
-        db.fetch_and_send_feeds(logger, fetcher, &sender, &options)?;
+        let report = if matches.is_present("STDIN") {
+            use std::io::Read;
+            let feed_urls = matches.values_of("FEED_URL").unwrap().collect::<Vec<_>>();
+            if feed_urls.len() != 1 {
+                return Err(Error::new("Reading from standard input requires exactly one feed URL").into_error());
+            }
+            db.feed(feed_urls[0])?; // the feed must exist
+            let mut body = Vec::new();
+            std::io::stdin().read_to_end(&mut body).map_err(|e| {
+                Error::new("Failed to read standard input")
+                    .with_cause(e)
+                    .into_error()
+            })?;
+            let fetcher = model::DocumentFetcher::new(body);
+            db.fetch_and_send_feeds(logger, fetcher, &sender, &options)?
+        } else {
+            let fetcher = model::NetFetcher::new(&fetcher_options(&config.fetch)?)?;
+            db.fetch_and_send_feeds(logger, fetcher, &sender, &options)?
+        };
+        db.commit()?;
+        if config.fetch.fail_on_feed_errors && !report.failures().is_empty() {
+            return Err(
+                Error::new(format!("Failed to fetch {} feed(s)", report.failures().len())).into_error(),
+            );
+        }
+    } else if let Some(matches) = matches.subcommand_matches("import-cookies") {
+        use std::io::Read;
+        let feed_url = matches.value_of("FEED_URL").unwrap();
+        let cookies_path = matches.value_of("COOKIES_FILE").unwrap();
+        let mut text = String::new();
+        std::fs::File::open(cookies_path)
+            .and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(|e| {
+                Error::new(format!("Failed to read cookies file {:?}", cookies_path))
+                    .with_cause(e)
+                    .into_error()
+            })?;
+        let cookies = cookies::CookieJar::parse_netscape(&text)?;
+        let db_config = config::DatabaseConfig::load(CONFIG_PATH)?;
+        let mut db = Database::open_with_config(DB_PATH, &db_config)?;
+        db.import_cookies(feed_url, cookies)?;
         db.commit()?;
+    } else if let Some(matches) = matches.subcommand_matches("info") {
+        let feed_url = matches.value_of("FEED_URL").unwrap();
+        let db_config = config::DatabaseConfig::load(CONFIG_PATH)?;
+        let db = Database::open_with_config(DB_PATH, &db_config)?;
+        let feed = db.feed(feed_url)?;
+        let time = |x: Option<chrono::DateTime<chrono::Utc>>, none: &str| {
+            x.map(|x| x.to_rfc3339()).unwrap_or(String::from(none))
+        };
+        let stdout = std::io::stdout();
+        let mut w = stdout.lock();
+        writeln!(w, "URL: {}", feed_url).unwrap();
+        writeln!(w, "Title: {}", feed.title().unwrap_or("n/a")).unwrap();
+        writeln!(w, "Items: {}", feed.num_items()).unwrap();
+        writeln!(w, "Added: {}", time(feed.added_at(), "n/a")).unwrap();
+        writeln!(w, "Last attempt: {}", time(feed.last_attempt(), "never")).unwrap();
+        writeln!(w, "Last success: {}", time(feed.last_success(), "never")).unwrap();
+        writeln!(w, "Next due: {}", time(feed.next_due(), "every run")).unwrap();
+        writeln!(
+            w,
+            "Last HTTP status: {}",
+            feed.last_http_status().map(|x| x.to_string()).unwrap_or(String::from("n/a"))
+        ).unwrap();
+        writeln!(w, "Last error: {}", feed.last_error().unwrap_or("n/a")).unwrap();
+        writeln!(w, "Consecutive failures: {}", feed.consecutive_failures()).unwrap();
+        writeln!(w, "Failing since: {}", time(feed.failing_since(), "n/a")).unwrap();
+        writeln!(
+            w,
+            "Disabled: {}",
+            match feed.disabled_at() {
+                None => String::from("no"),
+                Some(x) => format!("{} ({})", x.to_rfc3339(), feed.disabled_reason().unwrap_or("manually")),
+            }
+        ).unwrap();
+        for &(status, ref location) in feed.redirects() {
+            writeln!(w, "Redirected ({}): {}", status, location).unwrap();
+        }
     } else if let Some(_matches) = matches.subcommand_matches("list") {
-        let db = Database::open(DB_PATH)?;
+        let db_config = config::DatabaseConfig::load(CONFIG_PATH)?;
+        let db = Database::open_with_config(DB_PATH, &db_config)?;
         let stdout = std::io::stdout();
         let mut w = stdout.lock();
         for feed_url in db.feed_urls() {
             writeln!(w, "{}", feed_url).unwrap();
         }
+    } else if let Some(matches) = matches.subcommand_matches("migrate-storage") {
+        let kind = config::StorageKind::from_name(matches.value_of("STORAGE").unwrap()).unwrap();
+        let db_config = config::DatabaseConfig::load(CONFIG_PATH)?;
+        let mut db = Database::open_with_config(DB_PATH, &db_config)?;
+        db.convert_storage(kind)?;
+    } else if let Some(matches) = matches.subcommand_matches("move") {
+        let old_url = matches.value_of("OLD_URL").unwrap();
+        let new_url = matches.value_of("NEW_URL").unwrap();
+        let db_config = config::DatabaseConfig::load(CONFIG_PATH)?;
+        let mut db = Database::open_with_config(DB_PATH, &db_config)?;
+        db.move_feed(old_url, new_url, matches.is_present("MERGE"))?;
+        db.commit()?;
+
+        // The move doesn't carry over the feed's configuration, if any. The
+        // config file needn't be complete for this subcommand.
+
+        if let Ok(config) = config::Config::load(CONFIG_PATH) {
+            if config.feeds.contains_key(old_url) {
+                log::Logger::new(log::LogLevel::Important).log(
+                    log::LogLevel::Important,
+                    log::LogKind::Warning,
+                    model::moved_feed_config_warning(old_url, new_url),
+                );
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("prune") {
+        let db_config = config::DatabaseConfig::load(CONFIG_PATH)?;
+        let mut db = Database::open_with_config(DB_PATH, &db_config)?;
+        let num_pruned = db.prune(&retention_policy(&db_config));
+        db.commit()?;
+        if 0 < matches.occurrences_of("VERBOSE") {
+            println!("Pruned {} stale item(s)", num_pruned);
+        }
     } else if let Some(matches) = matches.subcommand_matches("remove") {
         let feed_url = matches.value_of("FEED_URL").unwrap();
-        let mut db = Database::open(DB_PATH)?;
+        let db_config = config::DatabaseConfig::load(CONFIG_PATH)?;
+        let mut db = Database::open_with_config(DB_PATH, &db_config)?;
         db.remove_feed(feed_url)?;
         db.commit()?;
+    } else if let Some(matches) = matches.subcommand_matches("restore") {
+        let backup = matches.value_of("BACKUP").unwrap();
+        let db_config = config::DatabaseConfig::load(CONFIG_PATH)?;
+        let mut db = Database::open_with_config(DB_PATH, &db_config)?;
+        db.restore_backup(backup)?;
     } else {
         app.print_help().unwrap();
         println!(); // print_help omits final newline
@@ -144,6 +480,101 @@ fn main_impl() -> Result<(), Error> {
     Ok(())
 }
 
+fn retention_policy(db_config: &config::DatabaseConfig) -> model::RetentionPolicy {
+    let mut policy = model::RetentionPolicy::new();
+    policy
+        .with_max_age(db_config.max_item_age_days.map(|x| chrono::Duration::days(x as i64)))
+        .with_max_items(db_config.max_items_per_feed);
+    policy
+}
+
+/// Picks one of the feeds an HTML page links to, asking the user unless told to
+/// pick the first.
+fn choose_feed_link(
+    page_url: &str,
+    mut links: Vec<discover::FeedLink>,
+    first: bool,
+) -> Result<discover::FeedLink, Error> {
+
+    if links.is_empty() {
+        return Err(Error::new(format!("Page links to no feeds (URL: {})", page_url)).into_error());
+    }
+
+    if first || links.len() == 1 {
+        return Ok(links.remove(0));
+    }
+
+    for (n, link) in links.iter().enumerate() {
+        println!(
+            "{}. {} — {} ({})",
+            n + 1,
+            link.title().unwrap_or("untitled"),
+            link.url(),
+            link.mime_type()
+        );
+    }
+    print!("Which feed? [1] ");
+    std::io::stdout().flush().unwrap();
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).map_err(|e| {
+        Error::new("Failed to read answer")
+            .with_cause(e)
+            .into_error()
+    })?;
+
+    let answer = answer.trim();
+    let n = if answer.is_empty() {
+        1
+    } else {
+        answer.parse::<usize>().unwrap_or(0)
+    };
+
+    if n == 0 || links.len() < n {
+        return Err(Error::new(format!("Invalid choice {:?}", answer)).into_error());
+    }
+
+    Ok(links.remove(n - 1))
+}
+
+fn fetcher_options(fetch_config: &config::FetchConfig) -> Result<model::NetFetcherOptions, Error> {
+    let mut options = model::NetFetcherOptions::new();
+    if let Some(x) = fetch_config.concurrency {
+        options.with_num_fetchers(x);
+    }
+    if let Some(x) = fetch_config.timeout_secs {
+        options.with_timeout(std::time::Duration::from_secs(x));
+    }
+    if let Some(x) = fetch_config.max_per_host {
+        options.with_max_per_host(x);
+    }
+    if let Some(x) = fetch_config.host_delay_millis {
+        options.with_host_delay(std::time::Duration::from_millis(x));
+    }
+    if let Some(x) = fetch_config.max_body_bytes {
+        options.with_max_body_size(x);
+    }
+    if let Some(x) = fetch_config.max_retries {
+        options.with_max_retries(x);
+    }
+    if let Some(x) = fetch_config.retry_budget_secs {
+        options.with_retry_budget(std::time::Duration::from_secs(x));
+    }
+    let mut proxies = proxy::ProxyRules::new();
+    if let Some(ref x) = fetch_config.http_proxy {
+        proxies.with_http(x)?;
+    }
+    if let Some(ref x) = fetch_config.https_proxy {
+        proxies.with_https(x)?;
+    }
+    proxies.with_no_proxy(fetch_config.no_proxy.iter().map(|x| x.as_str()));
+    options.with_proxies(proxies);
+    if let Some(ref x) = fetch_config.user_agent {
+        options.with_user_agent(x.as_str());
+    }
+    Ok(options)
+}
+
 fn main() {
     match main_impl() {
         Ok(..) => {}
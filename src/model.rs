@@ -1,29 +1,59 @@
-use {Error, FakeDebug, atom_syndication, futures, lettre, reqwest, rss, serde_json, std};
-use chrono::{DateTime, Utc};
-use config::Config;
+use {Error, FakeDebug, atom_syndication, brotli_decompressor, charset, command, flate2, futures, hyper, lettre, reqwest,
+     rss, serde_json, std};
+use chrono::{DateTime, Duration, Utc};
+use config::{Config, DatabaseConfig, FeedConfig, StorageKind};
+use cookies::CookieJar;
+use discover::{self, FeedLink};
 use escapade::Escapable;
+use lock::LockFile;
 use log::{LogKind, LogLevel, Logger};
+use proxy::{self, ProxyRules};
+use schedule::{self, Schedule};
 use std::collections::{HashMap, HashSet};
-use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use storage::{self, Storage};
 use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
-
-const NUM_FETCHERS: usize = 32;
-const CHANNEL_CAPACITY: usize = 2 * NUM_FETCHERS;
-const FETCH_TIMEOUT_SECS: u64 = 60;
+use throttle::HostThrottle;
+
+const DEFAULT_NUM_FETCHERS: usize = 32;
+const DEFAULT_FETCH_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_MAX_FETCHES_PER_HOST: usize = 4;
+const DEFAULT_MAX_BODY_SIZE: u64 = 16 * 1024 * 1024;
+const DEFAULT_MAX_RETRIES: u32 = 2;
+const DEFAULT_RETRY_BUDGET_SECS: u64 = 120;
+const RETRY_BASE_DELAY_MILLIS: u64 = 1000;
+const DEFAULT_MAX_BACKUPS: usize = 3;
+const DEFAULT_PERMANENT_REDIRECT_RUNS: u32 = 3;
+const MAX_REDIRECTS: usize = 10;
+const EXEC_PREFIX: &str = "exec:";
+const SCHEDULE_SLACK_SECS: i64 = 60;
+const DEFAULT_DISABLE_AFTER_DAYS: u32 = 28;
+
+/// `BACKUP_NAME_FORMAT` names each backup for the time it was taken, such that
+/// names sort chronologically.
+const BACKUP_NAME_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
 
 #[derive(Debug)]
 pub struct Database {
     path: PathBuf,
+    storage: Box<Storage>,
     feeds: HashMap<String, Feed>,
+    max_backups: usize,
+    backed_up: bool, // since the last commit
+    _lock: LockFile, // held for the life of the database
 }
 
 impl Database {
     pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Database::create_with_config(path, &DatabaseConfig::default())
+    }
+
+    pub fn create_with_config<P: AsRef<Path>>(path: P, config: &DatabaseConfig) -> Result<Self, Error> {
 
         let path = path.as_ref();
+        let lock = lock_database(path, config)?;
 
         match std::fs::metadata(path) {
             Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
@@ -39,77 +69,198 @@ impl Database {
 
         Ok(Database {
             path: PathBuf::from(path),
+            storage: storage::create(path, config.storage)?,
             feeds: HashMap::new(),
+            max_backups: config.max_backups.unwrap_or(DEFAULT_MAX_BACKUPS),
+            backed_up: false,
+            _lock: lock,
         })
     }
 
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Database::open_with_config(path, &DatabaseConfig::default())
+    }
 
-        let path = path.as_ref();
+    /// Opens the database, holding an exclusive lock on it until the
+    /// `Database` is dropped.
+    pub fn open_with_config<P: AsRef<Path>>(path: P, config: &DatabaseConfig) -> Result<Self, Error> {
 
-        let f = std::fs::File::open(path).map_err(|e| {
-            Error::new(format!("Failed to open database (path: {:?})", path))
-                .with_cause(e)
-                .into_error()
-        })?;
+        let path = path.as_ref();
+        let lock = lock_database(path, config)?;
 
-        let feeds = serde_json::from_reader(f).map_err(|e| {
-            Error::new(format!("Database is corrupt (path: {:?})", path))
-                .with_cause(e)
-                .into_error()
-        })?;
+        let mut storage = storage::open(path)?;
+        let feeds = storage.load()?;
 
         Ok(Database {
             path: PathBuf::from(path),
-            feeds,
+            storage: storage,
+            feeds: feeds,
+            max_backups: config.max_backups.unwrap_or(DEFAULT_MAX_BACKUPS),
+            backed_up: false,
+            _lock: lock,
         })
     }
 
-    pub fn commit(&self) -> Result<(), Error> {
+    /// Writes all changes to storage, first backing up the database as it
+    /// was before the changes.
+    pub fn commit(&mut self) -> Result<(), Error> {
+        self.back_up_before_changes()?;
+        self.storage.commit(&self.feeds)?;
+        self.backed_up = false;
+        Ok(())
+    }
 
-        // This employs the write-sync-rename pattern to guarantee an atomic
-        // update.
+    /// Backs up the database unless it's already been backed up since the last
+    /// commit. Storage that writes before the commit, as SQLite storage does
+    /// for each sent item, must be backed up before its first write.
+    fn back_up_before_changes(&mut self) -> Result<(), Error> {
+        if !self.backed_up {
+            self.back_up()?;
+            self.backed_up = true;
+        }
+        Ok(())
+    }
 
-        let working_path = {
-            let mut p = self.path.clone();
-            p.set_extension("working");
-            p
-        };
+    fn backup_dir(&self) -> PathBuf {
+        storage::sibling_path(&self.path, "backups")
+    }
 
-        let mut f = std::fs::File::create(&working_path).map_err(|e| {
-            Error::new(format!(
-                "Failed to create replacement database (path: {:?})",
-                working_path
-            )).with_cause(e)
-                .into_error()
-        })?;
+    fn back_up(&self) -> Result<(), Error> {
 
-        serde_json::to_writer(f.by_ref(), &self.feeds).map_err(
-            |e| {
-                Error::new(format!(
-                    "Failed to write feeds to database (path: {:?})",
-                    working_path
-                )).with_cause(e)
-                    .into_error()
-            },
-        )?;
+        if self.max_backups == 0 {
+            return Ok(());
+        }
 
-        f.write_all(b"\n").map_err(|e| {
-            Error::new(format!(
-                "Failed to write trailing newline to database (path: {:?})",
-                working_path
-            )).with_cause(e)
+        match std::fs::metadata(&self.path) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()), // nothing to back up
+            Err(e) => return Err(
+                Error::new(format!("Failed to obtain file metadata (path: {:?})", self.path))
+                    .with_cause(e)
+                    .into_error(),
+            ),
+            Ok(..) => {}
+        }
+
+        let backup_dir = self.backup_dir();
+
+        std::fs::create_dir_all(&backup_dir).map_err(|e| {
+            Error::new(format!("Failed to create backup directory (path: {:?})", backup_dir))
+                .with_cause(e)
                 .into_error()
         })?;
 
-        f.sync_all().map_err(|e| {
+        let backup_path = backup_dir.join(Utc::now().format(BACKUP_NAME_FORMAT).to_string());
+
+        std::fs::copy(&self.path, &backup_path).map_err(|e| {
             Error::new(format!(
-                "Failed to sync database to disk (path: {:?})",
-                working_path
+                "Failed to back up database (path: {:?}, backup path: {:?})",
+                self.path,
+                backup_path
             )).with_cause(e)
                 .into_error()
         })?;
 
+        for name in self.backups()?.iter().skip(self.max_backups) {
+            let expired_path = backup_dir.join(name);
+            std::fs::remove_file(&expired_path).map_err(|e| {
+                Error::new(format!("Failed to remove old backup (path: {:?})", expired_path))
+                    .with_cause(e)
+                    .into_error()
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the names of all backups, newest first.
+    pub fn backups(&self) -> Result<Vec<String>, Error> {
+
+        let backup_dir = self.backup_dir();
+
+        let entries = match std::fs::read_dir(&backup_dir) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(
+                Error::new(format!("Failed to read backup directory (path: {:?})", backup_dir))
+                    .with_cause(e)
+                    .into_error(),
+            ),
+            Ok(x) => x,
+        };
+
+        let mut names = Vec::new();
+
+        for entry in entries {
+            let entry = entry.map_err(|e| {
+                Error::new(format!("Failed to read backup directory (path: {:?})", backup_dir))
+                    .with_cause(e)
+                    .into_error()
+            })?;
+            if let Ok(name) = entry.file_name().into_string() {
+                names.push(name);
+            }
+        }
+
+        names.sort();
+        names.reverse();
+
+        Ok(names)
+    }
+
+    /// Replaces all feeds with those of the given backup. The replaced feeds
+    /// are themselves backed up, so a restore can be undone.
+    pub fn restore_backup(&mut self, name: &str) -> Result<(), Error> {
+
+        if !self.backups()?.iter().any(|x| x == name) {
+            return Err(
+                Error::new(format!("Backup does not exist (backup: {:?})", name)).into_error(),
+            );
+        }
+
+        // The backup is only read, so that restoring from it leaves it as it
+        // was, then written out through the live storage.
+
+        let mut backup = storage::open_read_only(&self.backup_dir().join(name))?;
+        self.feeds = backup.load()?;
+        self.commit()
+    }
+
+    /// Converts the database, in place, to another kind of storage.
+    pub fn convert_storage(&mut self, kind: StorageKind) -> Result<(), Error> {
+
+        if self.storage.kind() == kind {
+            return Err(
+                Error::new(format!(
+                    "Database already uses {} storage (path: {:?})",
+                    kind.name(),
+                    self.path
+                )).into_error(),
+            );
+        }
+
+        // Commit first so that nothing is left behind in the old storage's
+        // side files, such as the journal.
+
+        self.commit()?;
+
+        let working_path = storage::sibling_path(&self.path, "converting");
+
+        match std::fs::remove_file(&working_path) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(
+                Error::new(format!(
+                    "Failed to remove leftover converted database (path: {:?})",
+                    working_path
+                )).with_cause(e)
+                    .into_error(),
+            ),
+            Ok(..) => {}
+        }
+
+        {
+            let mut converted = storage::create(&working_path, kind)?;
+            converted.commit(&self.feeds)?;
+        }
+
         std::fs::rename(&working_path, &self.path).map_err(|e| {
             Error::new(format!(
                 "Failed to replace database (destination: {:?}, source: {:?})",
@@ -119,10 +270,118 @@ impl Database {
                 .into_error()
         })?;
 
+        self.storage = storage::open(&self.path)?;
+
         Ok(())
     }
 
+    /// Checks the database for problems, returning a description of each
+    /// problem found.
+    ///
+    /// Unlike opening the database, checking it doesn't stop at the first
+    /// corrupt feed. If `repair` is set and there are problems, every readable
+    /// feed is salvaged into a fresh database, and the original database is
+    /// kept alongside it.
+    pub fn check<P: AsRef<Path>>(path: P, config: &DatabaseConfig, repair: bool) -> Result<Vec<String>, Error> {
+
+        let path = path.as_ref();
+        let _lock = lock_database(path, config)?;
+        let mut problems = Vec::new();
+
+        for extension in &["working", "converting"] {
+            let leftover_path = storage::sibling_path(path, extension);
+            if leftover_path.exists() {
+                problems.push(format!(
+                    "Found leftover file from an interrupted commit (path: {:?})",
+                    leftover_path
+                ));
+                if repair {
+                    std::fs::remove_file(&leftover_path).map_err(|e| {
+                        Error::new(format!("Failed to remove leftover file (path: {:?})", leftover_path))
+                            .with_cause(e)
+                            .into_error()
+                    })?;
+                }
+            }
+        }
+
+        let mut storage = storage::open(path)?;
+
+        let raw_feeds = match storage.load_raw() {
+            Err(e) => {
+                if repair {
+                    return Err(Error::chain("Database cannot be salvaged", e).into_error());
+                }
+                problems.push(e.to_string());
+                return Ok(problems);
+            }
+            Ok(x) => x,
+        };
+
+        let mut salvaged = HashMap::new();
+
+        for (feed_url, raw) in raw_feeds {
+
+            // A feed URL may also be a command or a path, which `add` stores as
+            // an absolute path. Anything else must be a URL.
+
+            if !feed_url.starts_with(EXEC_PREFIX) && !Path::new(&feed_url).is_absolute() {
+                let result = reqwest::Url::parse(&feed_url)
+                    .map_err(|e| Error::new("Invalid URL").with_cause(e).into_error())
+                    .and_then(|_| local_path(&feed_url));
+                if let Err(e) = result {
+                    problems.push(format!("Feed URL is invalid (feed URL: {:?}): {}", feed_url, e));
+                }
+            }
+
+            let mut feed: Feed = match serde_json::from_value(raw) {
+                Err(e) => {
+                    problems.push(format!("Feed is corrupt (feed URL: {:?}): {}", feed_url, e));
+                    continue;
+                }
+                Ok(x) => x,
+            };
+
+            let num_duplicates = feed.remove_duplicate_items();
+            if 0 < num_duplicates {
+                problems.push(format!(
+                    "Feed has {} duplicate item(s) (feed URL: {:?})",
+                    num_duplicates,
+                    feed_url
+                ));
+            }
+
+            salvaged.insert(feed_url, feed);
+        }
+
+        if repair && !problems.is_empty() {
+
+            let backup_path = storage::sibling_path(path, "unrepaired");
+            std::fs::copy(path, &backup_path).map_err(|e| {
+                Error::new(format!(
+                    "Failed to back up database before repairing it (path: {:?}, backup path: {:?})",
+                    path,
+                    backup_path
+                )).with_cause(e)
+                    .into_error()
+            })?;
+
+            storage.commit(&salvaged)?;
+        }
+
+        Ok(problems)
+    }
+
     pub fn add_feed(&mut self, feed_url: &str) -> Result<(), Error> {
+        self.add_feed_with_title(feed_url, None)
+    }
+
+    /// Adds a feed whose title is already known, such as one found by
+    /// discovery.
+    pub fn add_feed_with_title(&mut self, feed_url: &str, title: Option<String>) -> Result<(), Error> {
+
+        let feed_url = canonical_feed_url(feed_url)?;
+        let feed_url = feed_url.as_str();
 
         if self.feeds.contains_key(feed_url) {
             return Err(
@@ -133,7 +392,10 @@ impl Database {
             );
         }
 
-        self.feeds.insert(String::from(feed_url), Feed::new());
+        let mut feed = Feed::new();
+        feed.title = title;
+        feed.added_at = Some(Utc::now());
+        self.feeds.insert(String::from(feed_url), feed);
 
         Ok(())
     }
@@ -150,17 +412,135 @@ impl Database {
         }
     }
 
+    /// Stops fetching a feed until it's enabled again.
+    pub fn disable_feed(&mut self, feed_url: &str) -> Result<(), Error> {
+        let feed = self.feed_mut(feed_url)?;
+        if feed.disabled_at.is_none() {
+            feed.disabled_at = Some(Utc::now());
+            feed.disabled_reason = None;
+        }
+        Ok(())
+    }
+
+    /// Resumes fetching a feed. Its failures so far no longer count toward
+    /// disabling it again.
+    pub fn enable_feed(&mut self, feed_url: &str) -> Result<(), Error> {
+        let feed = self.feed_mut(feed_url)?;
+        feed.disabled_at = None;
+        feed.disabled_reason = None;
+        feed.failing_since = None;
+        Ok(())
+    }
+
+    /// Re-keys a feed under a new URL, keeping its items so that none are sent
+    /// again.
+    ///
+    /// If the new URL is already in the database then the feeds are merged if
+    /// `merge` is set, and otherwise this fails.
+    pub fn move_feed(&mut self, old_url: &str, new_url: &str, merge: bool) -> Result<(), Error> {
+
+        if old_url == new_url {
+            return Err(
+                Error::new(format!(
+                    "Cannot move feed to its own URL (feed URL: {:?})",
+                    old_url
+                )).into_error(),
+            );
+        }
+
+        if !self.feeds.contains_key(old_url) {
+            return Err(
+                Error::new(format!(
+                    "Feed does not exist in database (feed URL: {:?})",
+                    old_url
+                )).into_error(),
+            );
+        }
+
+        if self.feeds.contains_key(new_url) && !merge {
+            return Err(
+                Error::new(format!(
+                    "Feed already exists in database (feed URL: {:?})",
+                    new_url
+                )).into_error(),
+            );
+        }
+
+        let old_feed = self.feeds.remove(old_url).unwrap();
+
+        match self.feeds.get_mut(new_url) {
+            None => {}
+            Some(new_feed) => {
+                new_feed.merge(old_feed);
+                return Ok(());
+            }
+        }
+
+        self.feeds.insert(String::from(new_url), old_feed);
+
+        Ok(())
+    }
+
+    /// Adds cookies to a feed's cookie jar, to be sent when fetching the feed.
+    pub fn import_cookies(&mut self, feed_url: &str, cookies: CookieJar) -> Result<(), Error> {
+        match self.feeds.get_mut(feed_url) {
+            None => Err(
+                Error::new(format!(
+                    "Feed does not exist in database (feed URL: {:?})",
+                    feed_url
+                )).into_error(),
+            ),
+            Some(feed) => {
+                feed.cookies.merge(cookies);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn feed(&self, feed_url: &str) -> Result<&Feed, Error> {
+        self.feeds.get(feed_url).ok_or_else(|| {
+            Error::new(format!(
+                "Feed does not exist in database (feed URL: {:?})",
+                feed_url
+            )).into_error()
+        })
+    }
+
+    fn feed_mut(&mut self, feed_url: &str) -> Result<&mut Feed, Error> {
+        self.feeds.get_mut(feed_url).ok_or_else(|| {
+            Error::new(format!(
+                "Feed does not exist in database (feed URL: {:?})",
+                feed_url
+            )).into_error()
+        })
+    }
+
     pub fn feed_urls<'a>(&'a self) -> Box<Iterator<Item = &'a str> + 'a> {
         Box::new(self.feeds.iter().map(|(k, _)| k.as_str()))
     }
 
+    /// Prunes stale items from every feed without fetching, returning the
+    /// number of items removed.
+    ///
+    /// Lacking a fresh copy of each feed, this treats the items observed
+    /// during the feed's most recent fetch as still present.
+    pub fn prune(&mut self, policy: &RetentionPolicy) -> usize {
+
+        let now = Utc::now();
+
+        self.feeds.values_mut().fold(0, |n, feed| {
+            let present = feed.most_recently_observed_item_ids();
+            n + feed.prune(policy, now, &present)
+        })
+    }
+
     pub fn fetch_and_send_feeds<F, S>(
         &mut self,
         logger: Arc<Logger>,
         fetcher: F,
         sender: &S,
         options: &FetchAndSendOptions,
-    ) -> Result<(), Error>
+    ) -> Result<FetchReport, Error>
     where
         F: Fetcher,
         S: Sender,
@@ -168,19 +548,58 @@ impl Database {
         // Pump the fetcher for the feeds it receives. Send each *new* item we
         // receive. Update database state as we go.
         //
-        // In case of error, stop all processing. Make sure that the database
+        // A feed that fails to fetch is recorded as such, and processing
+        // continues with the other feeds.
+        //
+        // In case of any other error, stop all processing. Make sure that the database
         // reflects all sent items but no unsent items.
 
+        // A feed that isn't due is skipped unless forced. A little slack lets
+        // a run that starts a few seconds early, as a cron job may, still
+        // fetch a feed that became due a period after the previous run.
+
+        let start = Utc::now();
         let feeds_to_fetch = self.feeds
-            .keys()
-            .filter(|x| options.should_fetch(x))
-            .map(|x| x.clone())
+            .iter()
+            .filter(|&(feed_url, _)| options.should_fetch(feed_url))
+            .filter(|&(feed_url, feed)| {
+                if feed.disabled_at.is_some() {
+                    logger.log(
+                        LogLevel::Verbose,
+                        LogKind::Info,
+                        format!("Skipping {}, which is disabled", feed_url),
+                    );
+                }
+                feed.disabled_at.is_none()
+            })
+            .filter(|&(feed_url, feed)| {
+                let due = options.force || feed.is_due(start + Duration::seconds(SCHEDULE_SLACK_SECS));
+                if !due {
+                    logger.log(
+                        LogLevel::Verbose,
+                        LogKind::Info,
+                        format!("Skipping {}, which is not due", feed_url),
+                    );
+                }
+                due
+            })
+            .map(|(feed_url, feed)| {
+                FetchRequest::new(feed_url, feed, options.feed_configs.get(feed_url))
+            })
             .collect::<Vec<_>>();
 
+        let mut moves = Vec::new();
+        let mut report = FetchReport::new();
+
+        // Sent items may be written to storage as soon as they're sent, so
+        // back up the database now, while it's as it was before the run.
+
+        self.back_up_before_changes()?;
+
         let mut spawn = futures::executor::spawn(fetcher.fetch(logger.clone(), feeds_to_fetch));
         'outer: while let Some(fetch_result) = spawn.wait_stream() {
 
-            let (feed_url, new_feed) = match fetch_result {
+            let outcome = match fetch_result {
                 Err(e) => {
                     logger.log(LogLevel::Important, LogKind::Error, e);
                     break;
@@ -188,13 +607,128 @@ impl Database {
                 Ok(x) => x,
             };
 
+            let feed_url = outcome.feed_url;
+            let old_feed = self.feeds.get_mut(&feed_url).unwrap();
+
+            let now = Utc::now();
+            old_feed.last_attempt = Some(now);
+            old_feed.last_http_status = outcome.http_status;
+            old_feed.cookies = outcome.cookies;
+
+            let new_feed = match outcome.result {
+                Err(e) => {
+                    logger.log(LogLevel::Important, LogKind::Error, &e);
+                    report.failures.push((feed_url.clone(), e.clone()));
+                    old_feed.last_error = Some(e);
+                    old_feed.consecutive_failures += 1;
+                    if old_feed.failing_since.is_none() {
+                        old_feed.failing_since = Some(now);
+                    }
+
+                    // A feed that's gone, or that has been failing for long
+                    // enough, is probably gone for good.
+
+                    let failing_since = old_feed.failing_since.unwrap();
+                    let reason = if outcome.http_status == Some(410) {
+                        Some(String::from("the server responded with 410 Gone"))
+                    } else if 0 < options.disable_after_days &&
+                               failing_since + Duration::days(options.disable_after_days as i64) <= now
+                    {
+                        Some(format!("failing since {}", failing_since.to_rfc3339()))
+                    } else {
+                        None
+                    };
+                    if let Some(reason) = reason {
+                        logger.log(
+                            LogLevel::Important,
+                            LogKind::Error,
+                            format!("Disabling {}, {}", feed_url, reason),
+                        );
+                        old_feed.disabled_at = Some(now);
+                        old_feed.disabled_reason = Some(reason);
+                    }
+                    continue;
+                }
+                Ok(x) => x,
+            };
+
+            report.num_fetched += 1;
+
+            old_feed.last_success = Some(now);
+            old_feed.last_error = None;
+            old_feed.consecutive_failures = 0;
+            old_feed.failing_since = None;
+
+            // The validators and the time the feed is next due are saved only
+            // once every new item is sent, lest the next fetch be answered with
+            // 304 Not Modified, or be put off, and the unsent items be lost or
+            // delayed.
+
+            let validators = if outcome.etag.is_some() || outcome.last_modified.is_some() {
+                Some((outcome.etag, outcome.last_modified))
+            } else {
+                None
+            };
+
+            let old_redirect = old_feed.permanent_redirect().map(String::from);
+            old_feed.redirects = outcome.redirects;
+            let runs = match old_feed.permanent_redirect() {
+                None => 0,
+                Some(x) if old_redirect.as_ref().map(|x| x.as_str()) == Some(x) => {
+                    old_feed.permanent_redirect_runs + 1
+                }
+                Some(_) => 1,
+            };
+            old_feed.permanent_redirect_runs = runs;
+
+            if 0 < options.permanent_redirect_runs &&
+                options.permanent_redirect_runs <= old_feed.permanent_redirect_runs
+            {
+                moves.push((feed_url.clone(), String::from(old_feed.permanent_redirect().unwrap())));
+            }
+
+            if let Some(ref x) = new_feed {
+                old_feed.schedule = x.schedule.clone();
+            }
+            let interval = options
+                .feed_configs
+                .get(&feed_url)
+                .and_then(|x| x.interval_minutes)
+                .map(schedule::configured_interval);
+            let next_due = old_feed.schedule.next_due(start, interval, outcome.max_age);
+
+            let new_feed = match new_feed {
+                None => {
+                    // The feed is unchanged, so whichever items were present
+                    // last time are present still.
+
+                    logger.log(
+                        LogLevel::Verbose,
+                        LogKind::Info,
+                        format!("Not modified {}", feed_url),
+                    );
+                    let present = old_feed.most_recently_observed_item_ids();
+                    for &mut (ref id, ref mut item) in old_feed.items.iter_mut() {
+                        if present.contains(id) {
+                            item.last_observed = now;
+                        }
+                    }
+                    if let Some((etag, last_modified)) = validators {
+                        old_feed.etag = etag;
+                        old_feed.last_modified = last_modified;
+                    }
+                    old_feed.next_due = next_due;
+                    continue;
+                }
+                Some(x) => x,
+            };
+
             let new_item_ids = new_feed
                 .items
                 .iter()
                 .map(|&(ref id, _)| id.clone())
                 .collect::<HashSet<_>>();
 
-            let old_feed = self.feeds.get_mut(&feed_url).unwrap();
             let old_item_ids = old_feed
                 .items
                 .iter()
@@ -220,7 +754,25 @@ impl Database {
                 old_feed.title = new_feed.title.clone();
             }
 
-            for (item_id, item) in new_items_in_order {
+            // Every item present in this fetch gets the same observation time,
+            // so that a later `prune` can tell which items were present.
+
+            for &mut (ref id, ref mut item) in old_feed.items.iter_mut() {
+                if new_item_ids.contains(id) {
+                    item.last_observed = now;
+                }
+            }
+
+            let num_pruned = old_feed.prune(&options.retention, now, &new_item_ids);
+            if 0 < num_pruned {
+                logger.log(
+                    LogLevel::Verbose,
+                    LogKind::Info,
+                    format!("Pruned {} stale item(s) from {}", num_pruned, feed_url),
+                );
+            }
+
+            for (item_id, mut item) in new_items_in_order {
 
                 logger.log(
                     LogLevel::Verbose,
@@ -233,6 +785,9 @@ impl Database {
                     ),
                 );
 
+                item.last_observed = now;
+                let mut stop = false;
+
                 if !options.no_send {
                     if let Err(e) = sender.send(&feed_url, &old_feed, &item_id, &item) {
                         logger.log(
@@ -246,13 +801,75 @@ impl Database {
                         );
                         break 'outer; // stop all processing
                     }
+
+                    // The item is sent, so record it in memory regardless,
+                    // but if storage fails to record it then we can no
+                    // longer guarantee against resending after a crash.
+
+                    if let Err(e) = self.storage.record_item(&feed_url, &item_id, &item) {
+                        logger.log(LogLevel::Important, LogKind::Error, e);
+                        stop = true;
+                    }
                 }
 
                 old_feed.items.push((item_id, item));
+
+                if stop {
+                    break 'outer; // stop all processing
+                }
+            }
+
+            if let Some((etag, last_modified)) = validators {
+                old_feed.etag = etag;
+                old_feed.last_modified = last_modified;
             }
+            old_feed.next_due = next_due;
         }
 
-        Ok(())
+        // Move feeds only after all feeds are processed, so that a feed moving
+        // onto one fetched later in this run doesn't have its items sent
+        // twice.
+
+        for (old_url, new_url) in moves {
+            self.move_feed(&old_url, &new_url, true)?;
+            if options.feed_configs.contains_key(&old_url) {
+                logger.log(
+                    LogLevel::Important,
+                    LogKind::Warning,
+                    moved_feed_config_warning(&old_url, &new_url),
+                );
+            }
+            let feed = self.feeds.get_mut(&new_url).unwrap();
+            feed.redirects.clear();
+            feed.permanent_redirect_runs = 0;
+            logger.log(
+                LogLevel::Important,
+                LogKind::Info,
+                format!("Moved {} to {}, which it permanently redirects to", old_url, new_url),
+            );
+        }
+
+        if !report.failures.is_empty() {
+            report.failures.sort();
+            logger.log(
+                LogLevel::Important,
+                LogKind::Error,
+                format!(
+                    "Failed to fetch {} of {} feed(s):",
+                    report.failures.len(),
+                    report.failures.len() + report.num_fetched
+                ),
+            );
+            for &(ref feed_url, ref e) in report.failures.iter() {
+                logger.log(
+                    LogLevel::Important,
+                    LogKind::Error,
+                    format!("    {} — {}", feed_url, e),
+                );
+            }
+        }
+
+        Ok(report)
     }
 }
 
@@ -260,6 +877,52 @@ impl Database {
 pub struct Feed {
     title: Option<String>,
     items: Vec<(String, FeedItem)>, // id to item
+
+    // Fetch metadata, absent from databases written before it existed:
+    #[serde(default)]
+    added_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    last_attempt: Option<DateTime<Utc>>,
+    #[serde(default)]
+    last_success: Option<DateTime<Utc>>,
+    #[serde(default)]
+    last_error: Option<String>,
+    #[serde(default)]
+    consecutive_failures: u32,
+    #[serde(default)]
+    failing_since: Option<DateTime<Utc>>,
+    #[serde(default)]
+    last_http_status: Option<u16>,
+
+    // When and why the feed stopped being fetched, if it has; no reason means
+    // it was disabled manually:
+    #[serde(default)]
+    disabled_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    disabled_reason: Option<String>,
+
+    // Validators for conditional GET:
+    #[serde(default)]
+    etag: Option<String>,
+    #[serde(default)]
+    last_modified: Option<String>,
+
+    // Redirects followed during the last successful fetch, and for how many
+    // successful fetches in a row they've led to the same permanent location:
+    #[serde(default)]
+    redirects: Vec<(u16, String)>,
+    #[serde(default)]
+    permanent_redirect_runs: u32,
+
+    #[serde(default)]
+    cookies: CookieJar,
+
+    // What the feed last said about how often to fetch it, and when it's next
+    // due to be fetched, if not at every run:
+    #[serde(default)]
+    schedule: Schedule,
+    #[serde(default)]
+    next_due: Option<DateTime<Utc>>,
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
@@ -278,7 +941,202 @@ impl Feed {
         Feed {
             title: None,
             items: Vec::new(),
+            added_at: None,
+            last_attempt: None,
+            last_success: None,
+            last_error: None,
+            consecutive_failures: 0,
+            failing_since: None,
+            last_http_status: None,
+            disabled_at: None,
+            disabled_reason: None,
+            etag: None,
+            last_modified: None,
+            redirects: Vec::new(),
+            permanent_redirect_runs: 0,
+            cookies: CookieJar::new(),
+            schedule: Schedule::new(),
+            next_due: None,
+        }
+    }
+
+    /// Folds another feed's items into this one, keeping this feed's copy of
+    /// any item both have.
+    fn merge(&mut self, other: Feed) {
+
+        let item_ids = self.items
+            .iter()
+            .map(|&(ref id, _)| id.clone())
+            .collect::<HashSet<_>>();
+
+        for (id, item) in other.items {
+            if !item_ids.contains(&id) {
+                self.items.push((id, item));
+            }
+        }
+
+        if self.title.is_none() {
+            self.title = other.title;
+        }
+
+        if other.added_at.is_some() && (self.added_at.is_none() || other.added_at < self.added_at) {
+            self.added_at = other.added_at;
+        }
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_ref().map(|x| x.as_str())
+    }
+
+    pub fn num_items(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn added_at(&self) -> Option<DateTime<Utc>> {
+        self.added_at
+    }
+
+    pub fn last_attempt(&self) -> Option<DateTime<Utc>> {
+        self.last_attempt
+    }
+
+    pub fn last_success(&self) -> Option<DateTime<Utc>> {
+        self.last_success
+    }
+
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_ref().map(|x| x.as_str())
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+
+    pub fn last_http_status(&self) -> Option<u16> {
+        self.last_http_status
+    }
+
+    /// Returns when the feed's current run of failures began, if it's failing.
+    pub fn failing_since(&self) -> Option<DateTime<Utc>> {
+        self.failing_since
+    }
+
+    pub fn disabled_at(&self) -> Option<DateTime<Utc>> {
+        self.disabled_at
+    }
+
+    pub fn disabled_reason(&self) -> Option<&str> {
+        self.disabled_reason.as_ref().map(|x| x.as_str())
+    }
+
+    pub fn redirects(&self) -> &[(u16, String)] {
+        &self.redirects
+    }
+
+    pub fn next_due(&self) -> Option<DateTime<Utc>> {
+        self.next_due
+    }
+
+    /// Returns whether the feed is due to be fetched at the given time.
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        self.next_due.map(|x| x <= now).unwrap_or(true) && !self.schedule.skips(now)
+    }
+
+    /// Returns the URL the feed permanently redirected to during its last
+    /// successful fetch, if any.
+    pub fn permanent_redirect(&self) -> Option<&str> {
+        self.redirects
+            .iter()
+            .take_while(|&&(status, _)| status == 301 || status == 308)
+            .last()
+            .map(|&(_, ref location)| location.as_str())
+    }
+
+    /// Removes all but the first of each item with the same ID, returning the
+    /// number of items removed.
+    fn remove_duplicate_items(&mut self) -> usize {
+        let num_before = self.items.len();
+        let mut seen = HashSet::new();
+        self.items.retain(|&(ref id, _)| seen.insert(id.clone()));
+        num_before - self.items.len()
+    }
+
+    fn most_recently_observed_item_ids(&self) -> HashSet<String> {
+        let newest = match self.items.iter().map(|&(_, ref item)| item.last_observed).max() {
+            None => return HashSet::new(),
+            Some(x) => x,
+        };
+        self.items
+            .iter()
+            .filter(|&&(_, ref item)| item.last_observed == newest)
+            .map(|&(ref id, _)| id.clone())
+            .collect()
+    }
+
+    /// Removes items that are absent from the feed and that the retention
+    /// policy no longer requires us to remember, returning the number of items
+    /// removed.
+    ///
+    /// Items present in the feed are never removed, lest they be sent again on
+    /// the next fetch.
+    fn prune(&mut self, policy: &RetentionPolicy, now: DateTime<Utc>, present: &HashSet<String>) -> usize {
+
+        let num_before = self.items.len();
+
+        if let Some(max_age) = policy.max_age {
+            let cutoff = now - max_age;
+            self.items.retain(|&(ref id, ref item)| {
+                present.contains(id) || cutoff <= item.last_observed
+            });
         }
+
+        if let Some(max_items) = policy.max_items {
+            if max_items < self.items.len() {
+                let mut absent = self.items
+                    .iter()
+                    .filter(|&&(ref id, _)| !present.contains(id))
+                    .map(|&(ref id, ref item)| (item.last_observed, id.clone()))
+                    .collect::<Vec<_>>();
+                absent.sort();
+                let excess = self.items.len() - max_items;
+                let doomed = absent
+                    .into_iter()
+                    .take(excess)
+                    .map(|(_, id)| id)
+                    .collect::<HashSet<_>>();
+                self.items.retain(|&(ref id, _)| !doomed.contains(id));
+            }
+        }
+
+        num_before - self.items.len()
+    }
+}
+
+/// `RetentionPolicy` specifies which absent feed items may be forgotten.
+///
+/// The default policy forgets nothing.
+#[derive(Clone, Debug, Default)]
+pub struct RetentionPolicy {
+    max_age: Option<Duration>,
+    max_items: Option<usize>,
+}
+
+impl RetentionPolicy {
+    pub fn new() -> Self {
+        RetentionPolicy {
+            max_age: None,
+            max_items: None,
+        }
+    }
+
+    pub fn with_max_age(&mut self, max_age: Option<Duration>) -> &mut Self {
+        self.max_age = max_age;
+        self
+    }
+
+    pub fn with_max_items(&mut self, max_items: Option<usize>) -> &mut Self {
+        self.max_items = max_items;
+        self
     }
 }
 
@@ -373,14 +1231,103 @@ impl Sender for EmailSender {
 }
 
 pub trait Fetcher {
-    type Stream: futures::Stream<Item = (String, Feed), Error = Error>;
-    fn fetch(self, logger: Arc<Logger>, feed_urls: Vec<String>) -> Self::Stream;
+    type Stream: futures::Stream<Item = FetchOutcome, Error = Error>;
+    fn fetch(self, logger: Arc<Logger>, requests: Vec<FetchRequest>) -> Self::Stream;
+}
+
+/// `FetchRequest` is what a fetcher needs to know to fetch one feed.
+#[derive(Clone, Debug)]
+pub struct FetchRequest {
+    feed_url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    cookies: CookieJar,
+    config: FeedConfig,
+}
+
+impl FetchRequest {
+    fn new(feed_url: &str, feed: &Feed, feed_config: Option<&FeedConfig>) -> Self {
+        FetchRequest {
+            feed_url: String::from(feed_url),
+            etag: feed.etag.clone(),
+            last_modified: feed.last_modified.clone(),
+            cookies: feed.cookies.clone(),
+            config: feed_config.cloned().unwrap_or_default(),
+        }
+    }
+}
+
+/// `FetchOutcome` is the result of fetching one feed.
+///
+/// The feed is `None` if the server says it hasn't changed since the request's
+/// validators were issued. The error is a message, not an `Error`, so that
+/// outcomes may cross threads.
+#[derive(Clone, Debug)]
+pub struct FetchOutcome {
+    feed_url: String,
+    http_status: Option<u16>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    redirects: Vec<(u16, String)>, // status and location of each hop
+    cookies: CookieJar,
+    max_age: Option<Duration>, // how long the response stays fresh
+    result: Result<Option<Feed>, String>,
+}
+
+impl FetchOutcome {
+    fn new(request: &FetchRequest) -> Self {
+        FetchOutcome {
+            feed_url: request.feed_url.clone(),
+            http_status: None,
+            etag: None,
+            last_modified: None,
+            redirects: Vec::new(),
+            cookies: request.cookies.clone(),
+            max_age: None,
+            result: Ok(None),
+        }
+    }
 }
 
+/// `FetchReport` summarizes a call to `Database::fetch_and_send_feeds`.
 #[derive(Debug, Default)]
+pub struct FetchReport {
+    num_fetched: usize,
+    failures: Vec<(String, String)>, // feed URL and error message
+}
+
+impl FetchReport {
+    fn new() -> Self {
+        FetchReport::default()
+    }
+
+    /// Returns how many feeds were fetched successfully, including those that
+    /// hadn't changed.
+    pub fn num_fetched(&self) -> usize {
+        self.num_fetched
+    }
+
+    /// Returns the URL of each feed that failed to fetch, along with why.
+    pub fn failures(&self) -> &[(String, String)] {
+        &self.failures
+    }
+}
+
+#[derive(Debug)]
 pub struct FetchAndSendOptions {
     feed_urls: Option<HashSet<String>>,
     no_send: bool,
+    retention: RetentionPolicy,
+    permanent_redirect_runs: u32,
+    feed_configs: HashMap<String, FeedConfig>,
+    force: bool,
+    disable_after_days: u32,
+}
+
+impl Default for FetchAndSendOptions {
+    fn default() -> Self {
+        FetchAndSendOptions::new()
+    }
 }
 
 impl FetchAndSendOptions {
@@ -388,19 +1335,55 @@ impl FetchAndSendOptions {
         FetchAndSendOptions {
             feed_urls: None,
             no_send: false,
+            retention: RetentionPolicy::new(),
+            permanent_redirect_runs: DEFAULT_PERMANENT_REDIRECT_RUNS,
+            feed_configs: HashMap::new(),
+            force: false,
+            disable_after_days: DEFAULT_DISABLE_AFTER_DAYS,
         }
     }
 
+    /// Applies per-feed settings, keyed by feed URL.
+    pub fn with_feed_configs(&mut self, feed_configs: HashMap<String, FeedConfig>) -> &mut Self {
+        self.feed_configs = feed_configs;
+        self
+    }
+
+    /// Moves a feed to the URL it permanently redirects to once it has done so
+    /// for this many fetches in a row. Zero means never.
+    pub fn with_permanent_redirect_runs(&mut self, runs: u32) -> &mut Self {
+        self.permanent_redirect_runs = runs;
+        self
+    }
+
     pub fn with_feed_urls<I: IntoIterator<Item = S>, S: Into<String>>(&mut self, feed_urls: I) -> &mut Self {
         self.feed_urls = Some(feed_urls.into_iter().map(|x| x.into()).collect());
         self
     }
 
+    /// Disables a feed once it has failed to fetch for this many days in a
+    /// row. Zero means never.
+    pub fn with_disable_after_days(&mut self, days: u32) -> &mut Self {
+        self.disable_after_days = days;
+        self
+    }
+
+    /// Fetches feeds even if they aren't due.
+    pub fn with_force(&mut self, force: bool) -> &mut Self {
+        self.force = force;
+        self
+    }
+
     pub fn with_no_send(&mut self, no_send: bool) -> &mut Self {
         self.no_send = no_send;
         self
     }
 
+    pub fn with_retention(&mut self, retention: RetentionPolicy) -> &mut Self {
+        self.retention = retention;
+        self
+    }
+
     fn should_fetch(&self, feed_url: &str) -> bool {
         if let Some(ref m) = self.feed_urls {
             return m.contains(feed_url);
@@ -410,162 +1393,903 @@ impl FetchAndSendOptions {
 }
 
 #[derive(Debug)]
-pub struct NetFetcher {
-    client: Arc<Mutex<reqwest::Client>>,
+pub struct NetFetcherOptions {
+    num_fetchers: usize,
+    timeout: std::time::Duration,
+    max_per_host: usize,
+    host_delay: std::time::Duration,
+    max_body_size: u64,
+    max_retries: u32,
+    retry_budget: std::time::Duration,
+    proxies: ProxyRules,
+    user_agent: String,
 }
 
-impl NetFetcher {
-    pub fn new() -> Result<Self, Error> {
+impl Default for NetFetcherOptions {
+    fn default() -> Self {
+        NetFetcherOptions::new()
+    }
+}
 
-        let mut client = reqwest::Client::new().map_err(|e| {
-            Error::new("Failed to construct HTTP client")
-                .with_cause(e)
-                .into_error()
-        })?;
+impl NetFetcherOptions {
+    pub fn new() -> Self {
+        NetFetcherOptions {
+            num_fetchers: DEFAULT_NUM_FETCHERS,
+            timeout: std::time::Duration::from_secs(DEFAULT_FETCH_TIMEOUT_SECS),
+            max_per_host: DEFAULT_MAX_FETCHES_PER_HOST,
+            host_delay: std::time::Duration::from_secs(0),
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_budget: std::time::Duration::from_secs(DEFAULT_RETRY_BUDGET_SECS),
+            proxies: ProxyRules::new(),
+            user_agent: format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        }
+    }
 
-        client.timeout(std::time::Duration::new(FETCH_TIMEOUT_SECS, 0));
+    /// Fetches up to this many feeds at once.
+    pub fn with_num_fetchers(&mut self, num_fetchers: usize) -> &mut Self {
+        self.num_fetchers = std::cmp::max(num_fetchers, 1);
+        self
+    }
 
-        Ok(NetFetcher { client: Arc::new(Mutex::new(client)) })
+    /// Gives up on a connection that stalls for this long.
+    pub fn with_timeout(&mut self, timeout: std::time::Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
     }
 
-    // It's kinda poor to wrap a channel in an Arc<Mutex<>>, but we need the
-    // Sync and Send traits.
-    fn fetch_thread(
-        logger: Arc<Logger>,
-        client: Arc<Mutex<reqwest::Client>>,
-        feed_urls: Arc<Mutex<Vec<String>>>,
-        send_chan: Arc<Mutex<futures::sink::Wait<futures::sync::mpsc::Sender<Result<(String, Feed), String>>>>>,
-    ) {
+    /// Fetches up to this many feeds from any one host at once.
+    pub fn with_max_per_host(&mut self, max_per_host: usize) -> &mut Self {
+        self.max_per_host = std::cmp::max(max_per_host, 1);
+        self
+    }
 
-        let fetch_it = |feed_url: &str| -> Result<String, Error> {
+    /// Waits at least this long between starting fetches from the same host.
+    pub fn with_host_delay(&mut self, host_delay: std::time::Duration) -> &mut Self {
+        self.host_delay = host_delay;
+        self
+    }
 
-            use std::io::Read;
+    /// Gives up on a feed whose body, once decompressed, is larger than this
+    /// many bytes.
+    pub fn with_max_body_size(&mut self, max_body_size: u64) -> &mut Self {
+        self.max_body_size = max_body_size;
+        self
+    }
 
-            logger.log(
-                LogLevel::Normal,
-                LogKind::Info,
-                format!("Fetching {}", feed_url),
-            );
+    /// Retries a request that fails transiently up to this many times.
+    pub fn with_max_retries(&mut self, max_retries: u32) -> &mut Self {
+        self.max_retries = max_retries;
+        self
+    }
 
-            let request = {
-                client.lock().unwrap().get(feed_url)
-            };
+    /// Starts no retry that would begin later than this long after the fetch
+    /// started, so that the run finishes in bounded time.
+    pub fn with_retry_budget(&mut self, retry_budget: std::time::Duration) -> &mut Self {
+        self.retry_budget = retry_budget;
+        self
+    }
 
-            let mut response = request.send().map_err(|e| {
-                Error::new(format!("Failed to fetch feed (feed URL: {})", feed_url))
-                    .with_cause(e)
-                    .into_error()
-            })?;
+    /// Sends requests through proxies, except for feeds that have a proxy of
+    /// their own.
+    pub fn with_proxies(&mut self, proxies: ProxyRules) -> &mut Self {
+        self.proxies = proxies;
+        self
+    }
 
-            let mut body = String::new();
-            response.read_to_string(&mut body).map_err(|e| {
-                Error::new(format!("Failed to read feed body (feed URL: {})", feed_url))
-                    .with_cause(e)
-                    .into_error()
-            })?;
+    /// Identifies as this user agent, except for feeds that have a user agent
+    /// of their own.
+    pub fn with_user_agent<S: Into<String>>(&mut self, user_agent: S) -> &mut Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+}
 
-            Ok(body)
-        };
+/// `HttpClients` holds an HTTP client for each proxy in use, building each as
+/// it's first needed. A proxy that the HTTP client can't use by itself is used
+/// through a relay of its own, which stops once the last clone of the
+/// `HttpClients` is dropped—i.e., once fetching is done.
+#[derive(Clone, Debug)]
+struct HttpClients {
+    timeout: std::time::Duration,
+    clients: Arc<FakeDebug<Mutex<HashMap<Option<reqwest::Url>, (reqwest::Client, Option<proxy::Relay>)>>>>,
+}
 
-        loop {
-            let feed_url = match feed_urls.lock().unwrap().pop() {
-                None => return, // no more feeds to fetch
-                Some(x) => x,
-            };
+impl HttpClients {
+    fn new(timeout: std::time::Duration) -> Self {
+        HttpClients {
+            timeout: timeout,
+            clients: Arc::new(FakeDebug(Mutex::new(HashMap::new()))),
+        }
+    }
 
-            // As soon as the channel closes, exit this thread.
+    fn get(&self, proxy: Option<&reqwest::Url>) -> Result<reqwest::Client, Error> {
 
-            match fetch_it(&feed_url) {
-                Err(e) => {
-                    match send_chan.lock().unwrap().send(Err(e.to_string())) {
-                        Err(_) => return, // channel closed
-                        Ok(_) => {}
-                    }
-                }
-                Ok(body) => {
+        let mut clients = self.clients.lock().unwrap();
+        let key = proxy.cloned();
 
-                    let feed = match parse_syndication(&feed_url, &body) {
-                        Err(e) => {
-                            logger.log(LogLevel::Important, LogKind::Error, e);
-                            continue;
-                        }
-                        Ok(x) => x,
-                    };
+        if let Some(&(ref client, _)) = clients.get(&key) {
+            return Ok(client.clone());
+        }
 
-                    match send_chan.lock().unwrap().send(Ok((feed_url, feed))) {
-                        Err(_) => return, // channel closed
-                        Ok(_) => {}
-                    }
-                }
-            }
+        let mut builder = reqwest::Client::builder();
+        builder
+            .timeout(self.timeout)
+            .redirect(reqwest::RedirectPolicy::none())
+            .gzip(false); // we decompress for ourselves, and more than gzip
+
+        let mut relay = None;
+
+        if let Some(proxy) = proxy {
+            let proxy = if proxy::needs_relay(proxy) {
+                let x = proxy::start_relay(proxy)?;
+                let url = x.url().clone();
+                relay = Some(x);
+                url
+            } else {
+                proxy.clone()
+            };
+            let proxy = reqwest::Proxy::all(proxy.clone()).map_err(|e| {
+                Error::new(format!("Failed to configure proxy {}", proxy))
+                    .with_cause(e)
+                    .into_error()
+            })?;
+            builder.proxy(proxy);
         }
+
+        let client = builder.build().map_err(|e| {
+            Error::new("Failed to construct HTTP client")
+                .with_cause(e)
+                .into_error()
+        })?;
+
+        clients.insert(key, (client.clone(), relay));
+        Ok(client)
     }
 }
 
-impl Fetcher for NetFetcher {
-    type Stream = NetFetcherStream;
-    fn fetch(self, logger: Arc<Logger>, feed_urls: Vec<String>) -> Self::Stream {
-
-        // We use (possibly) multiple fetcher threads, each of which sends
-        // the feeds it receives through a channel to the stream poller.
+/// `RetryBudget` decides whether and when to retry a request that failed
+/// transiently—i.e., a connection error, a timeout, or an HTTP status of 429 or
+/// 5xx.
+#[derive(Clone, Debug)]
+struct RetryBudget {
+    max_retries: u32,
+    deadline: std::time::Instant,
+}
 
-        let feed_urls = Arc::new(Mutex::new(feed_urls));
-        let (send_chan, recv_chan) = futures::sync::mpsc::channel(CHANNEL_CAPACITY);
+impl RetryBudget {
+    /// Returns how long to wait before the next retry, or `None` to give up.
+    fn delay(&self, retries: u32, retry_after: Option<std::time::Duration>) -> Option<std::time::Duration> {
 
-        let threads = (0..NUM_FETCHERS)
-            .into_iter()
-            .map(|_| {
-                let logger = logger.clone();
-                let client = self.client.clone();
-                let feed_urls = feed_urls.clone();
-                let send_chan = send_chan.clone();
-                std::thread::spawn(move || {
-                    use futures::Sink;
-                    Self::fetch_thread(
-                        logger,
-                        client,
-                        feed_urls,
-                        Arc::new(Mutex::new(send_chan.wait())),
-                    )
-                })
-            })
-            .collect();
+        if self.max_retries <= retries {
+            return None;
+        }
 
-        NetFetcherStream {
-            threads: threads,
-            recv_chan: recv_chan,
+        // Back off exponentially, with jitter so that threads that failed
+        // together don't retry together. The jitter needn't be unpredictable,
+        // only different between threads.
+
+        let delay = retry_after.unwrap_or_else(|| {
+            let max = RETRY_BASE_DELAY_MILLIS << std::cmp::min(retries, 16);
+            let nanos = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|x| x.subsec_nanos() as u64)
+                .unwrap_or(0);
+            std::time::Duration::from_millis(max / 2 + nanos % (max / 2 + 1))
+        });
+
+        if self.deadline < std::time::Instant::now() + delay {
+            return None;
         }
+
+        Some(delay)
     }
 }
 
-#[derive(Debug)]
-pub struct NetFetcherStream {
-    threads: Vec<std::thread::JoinHandle<()>>,
-    recv_chan: futures::sync::mpsc::Receiver<Result<(String, Feed), String>>,
+/// `FetchContext` is what every fetch shares, whether it's of a feed or of a
+/// page to discover feeds from.
+#[derive(Clone, Debug)]
+struct FetchContext {
+    clients: HttpClients,
+    proxies: Arc<ProxyRules>,
+    throttle: HostThrottle,
+    user_agent: String,
+    max_body_size: u64,
+    retry: RetryBudget,
 }
 
-impl futures::Stream for NetFetcherStream {
-    type Item = (String, Feed);
-    type Error = Error;
-    fn poll(&mut self) -> futures::Poll<Option<Self::Item>, Self::Error> {
-        match self.recv_chan.poll().unwrap() {
-            futures::Async::NotReady => Ok(futures::Async::NotReady),
-            futures::Async::Ready(None) => Ok(futures::Async::Ready(None)),
-            futures::Async::Ready(Some(Err(e))) => Err(Error::new(e).into_error()),
-            futures::Async::Ready(Some(Ok(x))) => Ok(futures::Async::Ready(Some(x))),
-        }
-    }
-}
+impl FetchContext {
+    /// Fetches and decodes a feed's body, recording in the outcome what else
+    /// the server says. Returns the body along with its media type, if known,
+    /// or none if the feed hasn't changed.
+    fn fetch_body(
+        &self,
+        logger: &Logger,
+        request: &FetchRequest,
+        outcome: &mut FetchOutcome,
+    ) -> Result<Option<(String, Option<String>)>, Error> {
 
-fn parse_syndication(feed_url: &str, body: &str) -> Result<Feed, Error> {
+        let feed_url = &request.feed_url;
 
-    // First try as RSS, then as Atom.
+        // A command's output is the feed body, and the command gets as
+        // long to run as an HTTP request gets to complete.
 
-    match rss::Channel::read_from(std::io::Cursor::new(body)) {
-        Err(..) => {}
-        Ok(channel) => {
-            return Ok(Feed {
-                title: Some(String::from(channel.title())),
+        if feed_url.starts_with(EXEC_PREFIX) {
+            let command_line = &feed_url[EXEC_PREFIX.len()..];
+            logger.log(
+                LogLevel::Normal,
+                LogKind::Info,
+                format!("Running {}", command_line),
+            );
+            let body = command::run(command_line, self.clients.timeout, self.max_body_size).map_err(|e| {
+                Error::chain(format!("Failed to fetch feed (feed URL: {})", feed_url), e).into_error()
+            })?;
+            let body = charset::decode(&body, None, request.config.encoding.as_ref().map(|x| x.as_str()))
+                .map_err(|e| {
+                    Error::chain(format!("Failed to decode feed body (feed URL: {})", feed_url), e).into_error()
+                })?;
+            return Ok(Some((body, None)));
+        }
+
+        // A local file needs neither throttling nor HTTP.
+
+        let path = local_path(feed_url).map_err(|e| {
+            Error::chain(format!("Invalid feed URL {:?}", feed_url), e).into_error()
+        })?;
+
+        if let Some(path) = path {
+            logger.log(
+                LogLevel::Normal,
+                LogKind::Info,
+                format!("Reading {}", path.display()),
+            );
+            let body = std::fs::File::open(&path)
+                .map_err(|e| {
+                    Error::new(format!("Failed to open feed file {:?}", path))
+                        .with_cause(e)
+                        .into_error()
+                })
+                .and_then(|f| read_limited(f, self.max_body_size))
+                .map_err(|e| {
+                    Error::chain(format!("Failed to read feed body (feed URL: {})", feed_url), e).into_error()
+                })?;
+            let body = charset::decode(&body, None, request.config.encoding.as_ref().map(|x| x.as_str()))
+                .map_err(|e| {
+                    Error::chain(format!("Failed to decode feed body (feed URL: {})", feed_url), e).into_error()
+                })?;
+            return Ok(Some((body, None)));
+        }
+
+        // Redirects may lead to other hosts, but it's the feed's own host
+        // that we're most likely to hit repeatedly.
+
+        let host = reqwest::Url::parse(feed_url)
+            .ok()
+            .and_then(|x| x.host_str().map(String::from))
+            .unwrap_or(feed_url.clone());
+        let _slot = self.throttle.acquire(&host);
+
+        logger.log(
+            LogLevel::Normal,
+            LogKind::Info,
+            format!("Fetching {}", feed_url),
+        );
+
+        let mut headers = reqwest::header::Headers::new();
+        headers.set_raw("Accept-Encoding", "gzip, deflate, br");
+        headers.set_raw(
+            "User-Agent",
+            request.config.user_agent.clone().unwrap_or(self.user_agent.clone()),
+        );
+        if let Some(ref etag) = request.etag {
+            headers.set_raw("If-None-Match", etag.clone());
+        }
+        if let Some(ref last_modified) = request.last_modified {
+            headers.set_raw("If-Modified-Since", last_modified.clone());
+        }
+
+        // Send the feed's credentials and headers only to the feed's own
+        // origin, not to any other it redirects to—not even the same host
+        // over plain HTTP.
+
+        let own_origin_headers = feed_headers(&request.config, &headers).map_err(|e| {
+            Error::chain(format!("Failed to prepare request (feed URL: {})", feed_url), e).into_error()
+        })?;
+
+        // A feed's own proxy, if any, applies to every request for the
+        // feed, including redirects.
+
+        let feed_proxy = match request.config.proxy {
+            None => None,
+            Some(ref x) if x.is_empty() => Some(None),
+            Some(ref x) => Some(Some(proxy::parse_proxy(x).map_err(|e| {
+                Error::chain(format!("Invalid proxy for feed (feed URL: {})", feed_url), e).into_error()
+            })?)),
+        };
+
+        // Follow redirects ourselves, so that we know which of them are
+        // permanent.
+
+        let mut url = reqwest::Url::parse(feed_url).map_err(|e| {
+            Error::new(format!("Invalid feed URL {:?}", feed_url))
+                .with_cause(e)
+                .into_error()
+        })?;
+        let own_url = url.clone();
+
+        let mut response = loop {
+
+            let proxy = match feed_proxy {
+                Some(ref x) => x.as_ref(),
+                None => self.proxies.proxy_for(&url),
+            };
+
+            let client = self.clients.get(proxy).map_err(|e| {
+                Error::chain(format!("Failed to fetch feed (feed URL: {})", feed_url), e).into_error()
+            })?;
+
+            let mut retries = 0;
+
+            let response = loop {
+
+                let mut hop_headers = if same_origin(&url, &own_url) {
+                    own_origin_headers.clone()
+                } else {
+                    headers.clone()
+                };
+                if let Some(x) = outcome.cookies.header_for(&url, Utc::now()) {
+                    hop_headers.set_raw("Cookie", x);
+                }
+
+                let mut request = client.get(url.clone());
+                request.headers(hop_headers);
+
+                let (e, delay) = match request.send() {
+                    Err(e) => {
+                        let transient = is_transient_error(&e);
+                        let e = Error::new(format!("Failed to fetch feed (feed URL: {})", feed_url))
+                            .with_cause(e)
+                            .into_error();
+                        match if transient { self.retry.delay(retries, None) } else { None } {
+                            None => return Err(e),
+                            Some(delay) => (e, delay),
+                        }
+                    }
+                    Ok(response) => {
+                        if let Some(x) = response.headers().get_raw("Set-Cookie") {
+                            for line in x.iter() {
+                                outcome.cookies.store_set_cookie(&url, &String::from_utf8_lossy(line), Utc::now());
+                            }
+                        }
+                        let status = response.status().as_u16();
+                        if status != 429 && (status < 500 || 600 <= status) {
+                            break response;
+                        }
+                        let retry_after = parse_retry_after(response.headers());
+                        let delay = match self.retry.delay(retries, retry_after) {
+                            None => break response, // let the caller report the status
+                            Some(x) => x,
+                        };
+                        let e = Error::new(format!(
+                            "Server responded with HTTP status {} (feed URL: {})",
+                            response.status(),
+                            feed_url
+                        )).into_error();
+                        (e, delay)
+                    }
+                };
+
+                logger.log(
+                    LogLevel::Verbose,
+                    LogKind::Info,
+                    format!(
+                        "Retrying {} in {} ms: {}",
+                        url,
+                        delay.as_secs() * 1000 + (delay.subsec_nanos() / 1_000_000) as u64,
+                        e
+                    ),
+                );
+
+                std::thread::sleep(delay);
+                retries += 1;
+            };
+
+            let status = response.status().as_u16();
+            match status {
+                301 | 302 | 303 | 307 | 308 => {}
+                _ => break response,
+            }
+
+            let location = raw_header(response.headers(), "Location").ok_or_else(|| {
+                Error::new(format!(
+                    "Server redirected without a location (feed URL: {})",
+                    feed_url
+                )).into_error()
+            })?;
+
+            let next = response.url().join(&location).map_err(|e| {
+                Error::new(format!(
+                    "Server redirected to an invalid location (feed URL: {}, location: {:?})",
+                    feed_url,
+                    location
+                )).with_cause(e)
+                    .into_error()
+            })?;
+
+            outcome.redirects.push((status, String::from(next.as_str())));
+
+            if MAX_REDIRECTS < outcome.redirects.len() {
+                return Err(
+                    Error::new(format!("Server redirected too many times (feed URL: {})", feed_url))
+                        .into_error(),
+                );
+            }
+
+            url = next;
+        };
+
+        outcome.http_status = Some(response.status().as_u16());
+        outcome.etag = raw_header(response.headers(), "ETag");
+        outcome.last_modified = raw_header(response.headers(), "Last-Modified");
+        outcome.max_age = schedule::cache_max_age(
+            raw_header(response.headers(), "Cache-Control").as_ref().map(|x| x.as_str()),
+            raw_header(response.headers(), "Expires").as_ref().map(|x| x.as_str()),
+            Utc::now(),
+        );
+
+        if response.status() == reqwest::StatusCode::NotModified {
+            return Ok(None);
+        }
+
+        if !response.status().is_success() {
+            return Err(
+                Error::new(format!(
+                    "Server responded with HTTP status {} (feed URL: {})",
+                    response.status(),
+                    feed_url
+                )).into_error(),
+            );
+        }
+
+        let body = read_body(&mut response, self.max_body_size).map_err(|e| {
+            Error::chain(format!("Failed to read feed body (feed URL: {})", feed_url), e).into_error()
+        })?;
+
+        let content_type = raw_header(response.headers(), "Content-Type");
+        let body = charset::decode(
+            &body,
+            content_type.as_ref().map(|x| x.as_str()),
+            request.config.encoding.as_ref().map(|x| x.as_str()),
+        ).map_err(|e| {
+            Error::chain(format!("Failed to decode feed body (feed URL: {})", feed_url), e).into_error()
+        })?;
+
+        Ok(Some((body, content_type)))
+    }
+}
+
+#[derive(Debug)]
+pub struct NetFetcher {
+    clients: HttpClients,
+    proxies: Arc<ProxyRules>,
+    num_fetchers: usize,
+    throttle: HostThrottle,
+    max_body_size: u64,
+    max_retries: u32,
+    retry_budget: std::time::Duration,
+    user_agent: String,
+}
+
+impl NetFetcher {
+    pub fn new(options: &NetFetcherOptions) -> Result<Self, Error> {
+
+        let clients = HttpClients::new(options.timeout);
+        clients.get(None)?; // fail now rather than once per feed
+
+        Ok(NetFetcher {
+            clients: clients,
+            proxies: Arc::new(options.proxies.clone()),
+            num_fetchers: options.num_fetchers,
+            throttle: HostThrottle::new(options.max_per_host, options.host_delay),
+            max_body_size: options.max_body_size,
+            max_retries: options.max_retries,
+            retry_budget: options.retry_budget,
+            user_agent: options.user_agent.clone(),
+        })
+    }
+
+    /// Finds out what's at a URL: a feed, or else an HTML page that may link
+    /// to feeds. The URL is fetched as a feed would be, with the given feed
+    /// settings, if any.
+    pub fn discover(&self, logger: &Logger, url: &str, feed_config: Option<&FeedConfig>) -> Result<Discovery, Error> {
+
+        let request = FetchRequest {
+            feed_url: String::from(url),
+            etag: None,
+            last_modified: None,
+            cookies: CookieJar::new(),
+            config: feed_config.cloned().unwrap_or_default(),
+        };
+        let mut outcome = FetchOutcome::new(&request);
+
+        let (body, content_type) = self.context()
+            .fetch_body(logger, &request, &mut outcome)?
+            .ok_or_else(|| {
+                Error::new(format!("Server responded with HTTP status 304 Not Modified (URL: {})", url)).into_error()
+            })?;
+
+        let is_html = content_type
+            .map(|x| x.to_lowercase().contains("html"))
+            .unwrap_or(false);
+
+        let e = match parse_syndication(url, &body) {
+            Ok(feed) => return Ok(Discovery::Feed(feed.title)),
+            Err(e) => e,
+        };
+
+        if !is_html {
+            return Err(e);
+        }
+
+        // The page's links are relative to wherever it redirected to.
+
+        let page_url = outcome.redirects.last().map(|&(_, ref x)| x.as_str()).unwrap_or(url);
+        let page_url = reqwest::Url::parse(page_url).map_err(|e| {
+            Error::new(format!("Invalid URL {:?}", page_url))
+                .with_cause(e)
+                .into_error()
+        })?;
+
+        Ok(Discovery::Page(discover::feed_links(&page_url, &body)))
+    }
+
+    fn context(&self) -> FetchContext {
+        FetchContext {
+            clients: self.clients.clone(),
+            proxies: self.proxies.clone(),
+            throttle: self.throttle.clone(),
+            user_agent: self.user_agent.clone(),
+            max_body_size: self.max_body_size,
+            retry: RetryBudget {
+                max_retries: self.max_retries,
+                deadline: std::time::Instant::now() + self.retry_budget,
+            },
+        }
+    }
+
+    // It's kinda poor to wrap a channel in an Arc<Mutex<>>, but we need the
+    // Sync and Send traits.
+    fn fetch_thread(
+        logger: Arc<Logger>,
+        context: FetchContext,
+        requests: Arc<Mutex<Vec<FetchRequest>>>,
+        send_chan: Arc<Mutex<futures::sink::Wait<futures::sync::mpsc::Sender<FetchOutcome>>>>,
+    ) {
+        loop {
+            let request = match requests.lock().unwrap().pop() {
+                None => return, // no more feeds to fetch
+                Some(x) => x,
+            };
+
+            let mut outcome = FetchOutcome::new(&request);
+            let result = context
+                .fetch_body(&logger, &request, &mut outcome)
+                .and_then(|body| match body {
+                    None => Ok(None),
+                    Some((body, _)) => parse_syndication(&request.feed_url, &body).map(Some),
+                })
+                .map_err(|e| e.to_string());
+            outcome.result = result;
+
+            // As soon as the channel closes, exit this thread.
+
+            match send_chan.lock().unwrap().send(outcome) {
+                Err(_) => return, // channel closed
+                Ok(_) => {}
+            }
+        }
+    }
+}
+
+/// Returns a warning that a moved feed's configuration, such as its
+/// credentials, applies no longer, being keyed by the feed's old URL.
+pub fn moved_feed_config_warning(old_url: &str, new_url: &str) -> String {
+    format!(
+        "The config file's [feeds.\"{}\"] section no longer applies; rename it to [feeds.\"{}\"]",
+        old_url,
+        new_url
+    )
+}
+
+/// Returns whether two URLs have the same scheme, host and port.
+fn same_origin(a: &reqwest::Url, b: &reqwest::Url) -> bool {
+    a.scheme() == b.scheme() && a.host_str() == b.host_str() && a.port_or_known_default() == b.port_or_known_default()
+}
+
+/// Adds a feed's credentials and custom headers to the request headers common to
+/// all feeds.
+fn feed_headers(
+    feed_config: &FeedConfig,
+    headers: &reqwest::header::Headers,
+) -> Result<reqwest::header::Headers, Error> {
+
+    use reqwest::header::{Authorization, Basic, Bearer};
+
+    let mut headers = headers.clone();
+
+    if let Some(ref username) = feed_config.username {
+        let password = match feed_config.password {
+            None => None,
+            Some(ref x) => Some(x.resolve()?),
+        };
+        headers.set(Authorization(Basic {
+            username: username.clone(),
+            password: password,
+        }));
+    }
+
+    if let Some(ref token) = feed_config.bearer_token {
+        headers.set(Authorization(Bearer { token: token.resolve()? }));
+    }
+
+    for (name, value) in feed_config.headers.iter() {
+        headers.set_raw(name.clone(), value.resolve()?);
+    }
+
+    Ok(headers)
+}
+
+/// Reads a response body, decompressing it according to its `Content-Encoding`
+/// header and failing if it exceeds `max_size` bytes.
+fn read_body<'a>(response: &'a mut reqwest::Response, max_size: u64) -> Result<Vec<u8>, Error> {
+    let content_encoding = raw_header(response.headers(), "Content-Encoding").map(|x| x.trim().to_lowercase());
+    let reader = decoding_reader(response, content_encoding.as_ref().map(|x| x.as_str()))?;
+    read_limited(reader, max_size)
+}
+
+/// Wraps a reader of a body in whichever decompressor its content encoding
+/// calls for.
+fn decoding_reader<'a, R: std::io::Read + 'a>(
+    reader: R,
+    content_encoding: Option<&str>,
+) -> Result<Box<std::io::Read + 'a>, Error> {
+    Ok(match content_encoding {
+        None | Some("") | Some("identity") => Box::new(reader),
+        Some("gzip") | Some("x-gzip") => Box::new(flate2::read::GzDecoder::new(reader)),
+        Some("deflate") => Box::new(flate2::read::ZlibDecoder::new(reader)),
+        Some("br") => Box::new(brotli_decompressor::Decompressor::new(reader, 4096)),
+        Some(x) => return Err(Error::new(format!("Unsupported content encoding {:?}", x)).into_error()),
+    })
+}
+
+/// Reads a body, failing if it exceeds the maximum size.
+fn read_limited<R: std::io::Read>(reader: R, max_size: u64) -> Result<Vec<u8>, Error> {
+
+    use std::io::Read;
+
+    let mut body = Vec::new();
+    reader.take(max_size.saturating_add(1)).read_to_end(&mut body).map_err(|e| {
+        Error::new("Failed to read or decompress body")
+            .with_cause(e)
+            .into_error()
+    })?;
+
+    if max_size < body.len() as u64 {
+        return Err(
+            Error::new(format!("Body exceeds the maximum size of {} bytes", max_size)).into_error(),
+        );
+    }
+
+    Ok(body)
+}
+
+/// Returns the feed URL to store for a new feed. A plain path is made absolute
+/// and canonical, so that the feed doesn't depend on the directory that
+/// `rss2email` runs in and so that one file can't be added twice.
+fn canonical_feed_url(feed_url: &str) -> Result<String, Error> {
+
+    if feed_url.starts_with(EXEC_PREFIX) {
+        return Ok(String::from(feed_url));
+    }
+
+    match reqwest::Url::parse(feed_url) {
+        Err(reqwest::UrlError::RelativeUrlWithoutBase) => {
+            let path = std::fs::canonicalize(feed_url).map_err(|e| {
+                Error::new(format!("Failed to resolve feed path {:?}", feed_url))
+                    .with_cause(e)
+                    .into_error()
+            })?;
+            path.into_os_string().into_string().map_err(|x| {
+                Error::new(format!("Feed path is not valid Unicode (path: {:?})", x)).into_error()
+            })
+        }
+        _ => Ok(String::from(feed_url)),
+    }
+}
+
+/// Returns the path of a feed that's a local file—i.e., one whose URL is a
+/// `file://` URL or a plain path rather than an HTTP(S) URL.
+fn local_path(feed_url: &str) -> Result<Option<std::path::PathBuf>, Error> {
+    match reqwest::Url::parse(feed_url) {
+        Err(reqwest::UrlError::RelativeUrlWithoutBase) => Ok(Some(std::path::PathBuf::from(feed_url))),
+        Err(e) => Err(Error::new("Invalid URL").with_cause(e).into_error()),
+        Ok(ref url) if url.scheme() == "file" => url.to_file_path().map(Some).map_err(|_| {
+            Error::new("A file URL must name a local path").into_error()
+        }),
+        Ok(_) => Ok(None),
+    }
+}
+
+/// Returns whether a failed request might succeed if retried, which is so only
+/// if connecting failed or the request timed out. Other errors, such as a
+/// malformed response or a TLS failure, would only fail again.
+fn is_transient_error(e: &reqwest::Error) -> bool {
+
+    let cause = match e.get_ref() {
+        None => return false,
+        Some(x) => x,
+    };
+
+    if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+        return is_transient_io_error(e.kind());
+    }
+
+    match cause.downcast_ref::<hyper::Error>() {
+        Some(&hyper::Error::Io(ref e)) => is_transient_io_error(e.kind()),
+        Some(&hyper::Error::Timeout) => true,
+        _ => false,
+    }
+}
+
+fn is_transient_io_error(kind: std::io::ErrorKind) -> bool {
+    use std::io::ErrorKind::*;
+    match kind {
+        ConnectionRefused | ConnectionReset | ConnectionAborted | NotConnected | AddrNotAvailable | BrokenPipe |
+        TimedOut | WouldBlock | Interrupted => true,
+        _ => false,
+    }
+}
+
+/// Parses a `Retry-After` header, which holds either a number of seconds or an
+/// HTTP date.
+fn parse_retry_after(headers: &reqwest::header::Headers) -> Option<std::time::Duration> {
+
+    let value = raw_header(headers, "Retry-After")?;
+    let value = value.trim();
+
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(std::time::Duration::from_secs(secs));
+    }
+
+    let when = DateTime::parse_from_rfc2822(value).ok()?;
+    let delay = when.with_timezone(&Utc).signed_duration_since(Utc::now());
+    Some(delay.to_std().unwrap_or(std::time::Duration::from_secs(0)))
+}
+
+fn raw_header(headers: &reqwest::header::Headers, name: &str) -> Option<String> {
+    headers
+        .get_raw(name)
+        .and_then(|x| x.one())
+        .map(|x| String::from_utf8_lossy(x).into_owned())
+}
+
+impl Fetcher for NetFetcher {
+    type Stream = NetFetcherStream;
+    fn fetch(self, logger: Arc<Logger>, requests: Vec<FetchRequest>) -> Self::Stream {
+
+        // We use (possibly) multiple fetcher threads, each of which sends
+        // the feeds it receives through a channel to the stream poller.
+
+        let requests = Arc::new(Mutex::new(requests));
+        let context = self.context();
+        let (send_chan, recv_chan) = futures::sync::mpsc::channel(2 * self.num_fetchers);
+
+        let threads = (0..self.num_fetchers)
+            .into_iter()
+            .map(|_| {
+                let logger = logger.clone();
+                let context = context.clone();
+                let requests = requests.clone();
+                let send_chan = send_chan.clone();
+                std::thread::spawn(move || {
+                    use futures::Sink;
+                    Self::fetch_thread(
+                        logger,
+                        context,
+                        requests,
+                        Arc::new(Mutex::new(send_chan.wait())),
+                    )
+                })
+            })
+            .collect();
+
+        NetFetcherStream {
+            threads: threads,
+            recv_chan: recv_chan,
+        }
+    }
+}
+
+/// `Discovery` is what's at a URL that might be a feed.
+#[derive(Clone, Debug)]
+pub enum Discovery {
+    Feed(Option<String>), // the feed's title
+    Page(Vec<FeedLink>),  // an HTML page, with the feeds it links to
+}
+
+#[derive(Debug)]
+pub struct NetFetcherStream {
+    threads: Vec<std::thread::JoinHandle<()>>,
+    recv_chan: futures::sync::mpsc::Receiver<FetchOutcome>,
+}
+
+impl futures::Stream for NetFetcherStream {
+    type Item = FetchOutcome;
+    type Error = Error;
+    fn poll(&mut self) -> futures::Poll<Option<Self::Item>, Self::Error> {
+        match self.recv_chan.poll().unwrap() {
+            futures::Async::NotReady => Ok(futures::Async::NotReady),
+            futures::Async::Ready(None) => Ok(futures::Async::Ready(None)),
+            futures::Async::Ready(Some(x)) => Ok(futures::Async::Ready(Some(x))),
+        }
+    }
+}
+
+/// `DocumentFetcher` "fetches" a feed document already in hand—e.g., one read
+/// from standard input—as the body of each feed it's asked for.
+#[derive(Debug)]
+pub struct DocumentFetcher {
+    body: Vec<u8>,
+}
+
+impl DocumentFetcher {
+    pub fn new(body: Vec<u8>) -> Self {
+        DocumentFetcher { body: body }
+    }
+}
+
+impl Fetcher for DocumentFetcher {
+    type Stream = futures::stream::Iter<std::vec::IntoIter<Result<FetchOutcome, Error>>>;
+    fn fetch(self, _logger: Arc<Logger>, requests: Vec<FetchRequest>) -> Self::Stream {
+        let body = self.body;
+        futures::stream::iter(
+            requests
+                .into_iter()
+                .map(|request| {
+                    let result = charset::decode(&body, None, request.config.encoding.as_ref().map(|x| x.as_str()))
+                        .and_then(|x| parse_syndication(&request.feed_url, &x))
+                        .map(Some)
+                        .map_err(|e| e.to_string());
+                    Ok(FetchOutcome {
+                        feed_url: request.feed_url,
+                        http_status: None,
+                        etag: request.etag,
+                        last_modified: request.last_modified,
+                        redirects: Vec::new(),
+                        cookies: request.cookies,
+                        max_age: None,
+                        result: result,
+                    })
+                })
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+fn lock_database(db_path: &Path, config: &DatabaseConfig) -> Result<LockFile, Error> {
+    let wait = std::time::Duration::from_secs(config.lock_wait_secs.unwrap_or(0));
+    LockFile::acquire(storage::sibling_path(db_path, "lock"), wait)
+}
+
+fn parse_syndication(feed_url: &str, body: &str) -> Result<Feed, Error> {
+
+    // First try as RSS, then as Atom.
+
+    match rss::Channel::read_from(std::io::Cursor::new(body)) {
+        Err(..) => {}
+        Ok(channel) => {
+            return Ok(Feed {
+                title: Some(String::from(channel.title())),
                 items: channel
                     .items()
                     .iter()
@@ -583,176 +2307,1152 @@ fn parse_syndication(feed_url: &str, body: &str) -> Result<Feed, Error> {
                             id,
                             FeedItem {
                                 last_observed: DateTime::from(SystemTime::now()),
-                                title: item.title().map(|x| String::from(x)),
-                                link: item.link().map(|x| String::from(x)),
-                                content: item.content().or(item.description()).map(
-                                    |x| String::from(x),
-                                ),
-                            },
-                        ))
-                    })
-                    .collect::<Result<_, _>>()?,
-            });
+                                title: item.title().map(|x| String::from(x)),
+                                link: item.link().map(|x| String::from(x)),
+                                content: item.content().or(item.description()).map(
+                                    |x| String::from(x),
+                                ),
+                            },
+                        ))
+                    })
+                    .collect::<Result<_, _>>()?,
+                schedule: Schedule::from_rss(&channel),
+                ..Feed::new()
+            });
+        }
+    }
+
+    // JSON Feed is the odd one out, being JSON.
+
+    if body.trim_left().starts_with('{') {
+        return parse_json_feed(feed_url, body);
+    }
+
+    let raw = atom_syndication::Feed::from_str(body).map_err(|e| {
+        Error::new(format!("Failed to parse feed (feed URL: {})", feed_url))
+            .with_cause(e)
+            .into_error()
+    })?;
+
+    Ok(Feed {
+        title: Some(String::from(raw.title())),
+        items: raw.entries()
+            .iter()
+            .map(|entry| {
+                (
+                    String::from(entry.id()),
+                    FeedItem {
+                        last_observed: DateTime::from(SystemTime::now()),
+                        title: Some(String::from(entry.title())),
+                        link: entry.links().first().map(|x| String::from(x.href())),
+                        content: entry.content().and_then(|x| x.value()).map(
+                            |x| String::from(x),
+                        ),
+                    },
+                )
+            })
+            .collect(),
+        ..Feed::new()
+    })
+}
+
+fn parse_json_feed(feed_url: &str, body: &str) -> Result<Feed, Error> {
+
+    #[derive(Deserialize)]
+    struct JsonFeed {
+        title: Option<String>,
+        #[serde(default)]
+        items: Vec<JsonFeedItem>,
+    }
+
+    #[derive(Deserialize)]
+    struct JsonFeedItem {
+        id: serde_json::Value, // a string, though some feeds use numbers
+        url: Option<String>,
+        title: Option<String>,
+        content_html: Option<String>,
+        content_text: Option<String>,
+    }
+
+    let raw: JsonFeed = serde_json::from_str(body).map_err(|e| {
+        Error::new(format!("Failed to parse feed (feed URL: {})", feed_url))
+            .with_cause(e)
+            .into_error()
+    })?;
+
+    Ok(Feed {
+        title: raw.title,
+        items: raw.items
+            .into_iter()
+            .map(|item| {
+                let id = match item.id {
+                    serde_json::Value::String(x) => x,
+                    x => x.to_string(),
+                };
+                (
+                    id,
+                    FeedItem {
+                        last_observed: DateTime::from(SystemTime::now()),
+                        title: item.title,
+                        link: item.url,
+                        content: item.content_html.or(item.content_text),
+                    },
+                )
+            })
+            .collect(),
+        ..Feed::new()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Secret;
+    use std::io::{Read, Write};
+    use tempdir::TempDir;
+
+    const TEST_PATH_PREFIX: &str = "rss2email";
+
+    #[derive(Debug)]
+    pub struct RecorderSender {
+        recorded_items: Mutex<Vec<(String, String)>>,
+    }
+
+    impl RecorderSender {
+        pub fn new() -> Self {
+            RecorderSender { recorded_items: Mutex::new(Vec::new()) }
+        }
+
+        pub fn recorded_items(self) -> Vec<(String, String)> {
+            self.recorded_items.into_inner().unwrap()
+        }
+    }
+
+    impl Sender for RecorderSender {
+        fn send(&self, feed_url: &str, _feed: &Feed, feed_item_id: &str, _feed_item: &FeedItem) -> Result<(), Error> {
+            self.recorded_items.lock().unwrap().push((
+                String::from(feed_url),
+                String::from(
+                    feed_item_id,
+                ),
+            ));
+            Ok(())
+        }
+    }
+
+    pub struct FailingSender;
+
+    impl Sender for FailingSender {
+        fn send(&self, _feed_url: &str, _feed: &Feed, _feed_item_id: &str, _feed_item: &FeedItem) -> Result<(), Error> {
+            Err(Error::new("Sending is broken").into_error())
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct MockFetcher {
+        mock_outcomes: Vec<FetchOutcome>,
+    }
+
+    impl Fetcher for MockFetcher {
+        type Stream = futures::stream::Iter<std::vec::IntoIter<Result<FetchOutcome, Error>>>;
+        fn fetch(self, _logger: Arc<Logger>, _requests: Vec<FetchRequest>) -> Self::Stream {
+            futures::stream::iter(self.mock_outcomes.into_iter().map(Ok).collect::<Vec<_>>())
+        }
+    }
+
+    impl From<Vec<(String, Result<Feed, String>)>> for MockFetcher {
+        fn from(mock_items: Vec<(String, Result<Feed, String>)>) -> Self {
+            MockFetcher {
+                mock_outcomes: mock_items
+                    .into_iter()
+                    .map(|(feed_url, result)| {
+                        FetchOutcome {
+                            feed_url: feed_url,
+                            http_status: result.as_ref().ok().map(|_| 200),
+                            etag: None,
+                            last_modified: None,
+                            redirects: Vec::new(),
+                            cookies: CookieJar::new(),
+                            max_age: None,
+                            result: result.map(Some),
+                        }
+                    })
+                    .collect(),
+            }
+        }
+    }
+
+    #[test]
+    fn rss_content_is_in_description() {
+
+        let source = r#"<rss version="2.0">
+<channel>
+<title>alpha</title>
+<link>http://bravo</link>
+<description>charlie</description>
+<item>
+<title>delta</title>
+<link>http://echo</link>
+<description>foxtrot</description>
+<guid>golf</guid>
+</item>
+</channel>
+</rss>"#;
+
+        let got = super::parse_syndication("http://example.com", source).unwrap();
+
+        let expected = Feed {
+            title: Some(String::from("alpha")),
+            items: vec![
+                (
+                    String::from("golf"),
+                    FeedItem {
+                        last_observed: got.items[0].1.last_observed,
+                        title: Some(String::from("delta")),
+                        link: Some(String::from("http://echo")),
+                        content: Some(String::from("foxtrot")),
+                    }
+                ),
+            ].into_iter()
+                .collect(),
+            ..Feed::new()
+        };
+
+        assert_eq!(got, expected);
+    }
+
+    #[test]
+    fn creating_a_database_requires_it_to_not_exist() {
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let db_path = tdir.path().join("foo");
+        Database::create(&db_path).unwrap().commit().unwrap();
+        Database::create(&db_path).unwrap_err();
+    }
+
+    #[test]
+    fn opening_a_database_requires_it_to_exist() {
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let db_path = tdir.path().join("foo");
+        Database::open(&db_path).unwrap_err();
+        Database::create(&db_path).unwrap().commit().unwrap();
+        Database::open(&db_path).unwrap();
+    }
+
+    #[test]
+    fn opening_a_database_requires_it_to_be_unlocked() {
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let db_path = tdir.path().join("foo");
+        Database::create(&db_path).unwrap().commit().unwrap();
+        let db = Database::open(&db_path).unwrap();
+        let e = Database::open(&db_path).unwrap_err();
+        assert!(e.to_string().contains(&std::process::id().to_string()));
+        drop(db);
+        Database::open(&db_path).unwrap();
+    }
+
+    #[test]
+    fn checking_reports_feed_urls_that_are_neither_urls_nor_absolute_paths() {
+
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let db_path = tdir.path().join("foo");
+        let content = r#"{"version":1,"feeds":{
+            "http://example.com/alpha":{"title":"Alpha","items":[]},
+            "/var/feeds/bravo.xml":{"title":"Bravo","items":[]},
+            "exec:./make-feed charlie":{"title":"Charlie","items":[]},
+            "example.com/delta":{"title":"Delta","items":[]}}}"#;
+        std::fs::File::create(&db_path).unwrap().write_all(content.as_bytes()).unwrap();
+
+        let problems = Database::check(&db_path, &DatabaseConfig::default(), false).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("example.com/delta"));
+    }
+
+    #[test]
+    fn converting_storage_keeps_feeds() {
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let db_path = tdir.path().join("foo");
+        let mut db = Database::create(&db_path).unwrap();
+        db.add_feed("https://xkcd.com/rss.xml").unwrap();
+        db.commit().unwrap();
+        db.convert_storage(StorageKind::Sqlite).unwrap();
+        db.convert_storage(StorageKind::Sqlite).unwrap_err();
+        drop(db);
+        let db = Database::open(&db_path).unwrap();
+        assert_eq!(db.feed_urls().collect::<Vec<_>>(), vec!["https://xkcd.com/rss.xml"]);
+    }
+
+    #[test]
+    fn repairing_a_database_salvages_readable_feeds() {
+
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let db_path = tdir.path().join("foo");
+        let content = r#"{"version":1,"feeds":{
+            "http://example.com/alpha":{"title":"Alpha","items":[
+                ["id alpha",{"last_observed":"2017-07-01T00:00:00Z"}],
+                ["id alpha",{"last_observed":"2017-07-01T00:00:00Z"}]]},
+            "http://example.com/bravo":{"title":"Bravo","items":"garbage"}}}"#;
+        std::fs::File::create(&db_path).unwrap().write_all(content.as_bytes()).unwrap();
+        Database::open(&db_path).unwrap_err();
+
+        let config = DatabaseConfig::default();
+        assert_eq!(Database::check(&db_path, &config, false).unwrap().len(), 2);
+        assert_eq!(Database::check(&db_path, &config, true).unwrap().len(), 2);
+        assert_eq!(Database::check(&db_path, &config, false).unwrap().len(), 0);
+
+        let db = Database::open(&db_path).unwrap();
+        assert_eq!(db.feed_urls().collect::<Vec<_>>(), vec!["http://example.com/alpha"]);
+        assert_eq!(db.feeds["http://example.com/alpha"].items.len(), 1);
+    }
+
+    #[test]
+    fn restoring_a_backup_undoes_later_commits() {
+
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let db_path = tdir.path().join("foo");
+        let mut config = DatabaseConfig::default();
+        config.max_backups = Some(2);
+
+        let mut db = Database::create_with_config(&db_path, &config).unwrap();
+        db.commit().unwrap();
+        assert_eq!(db.backups().unwrap().len(), 0);
+
+        for feed_url in &["http://example.com/alpha", "http://example.com/bravo", "http://example.com/charlie"] {
+            std::thread::sleep(std::time::Duration::from_millis(10)); // for distinct backup names
+            db.add_feed(feed_url).unwrap();
+            db.commit().unwrap();
+        }
+
+        let backups = db.backups().unwrap();
+        assert_eq!(backups.len(), 2);
+
+        let backup_path = db.backup_dir().join(&backups[1]);
+        let mut backup_content = Vec::new();
+        std::fs::File::open(&backup_path).unwrap().read_to_end(&mut backup_content).unwrap();
+
+        db.restore_backup(&backups[1]).unwrap();
+        let mut feed_urls = db.feed_urls().collect::<Vec<_>>();
+        feed_urls.sort();
+        assert_eq!(feed_urls, vec!["http://example.com/alpha"]);
+
+        // The backup was left as it was, with nothing, such as a journal,
+        // created beside it.
+
+        let mut content = Vec::new();
+        std::fs::File::open(&backup_path).unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, backup_content);
+        assert_eq!(std::fs::read_dir(db.backup_dir()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn backups_predate_items_recorded_during_the_run() {
+
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let db_path = tdir.path().join("foo");
+        let mut config = DatabaseConfig::default();
+        config.storage = StorageKind::Sqlite;
+
+        let mut db = Database::create_with_config(&db_path, &config).unwrap();
+        db.add_feed("http://example.com").unwrap();
+        db.commit().unwrap();
+
+        let fetcher = MockFetcher::from(vec![
+            (
+                String::from("http://example.com"),
+                Ok(Feed {
+                    title: Some(String::from("Example")),
+                    items: vec![
+                        (
+                            String::from("id alpha"),
+                            FeedItem {
+                                last_observed: DateTime::from(SystemTime::now()),
+                                title: Some(String::from("entry alpha")),
+                                link: Some(String::from("http://example.com/alpha")),
+                                content: Some(String::from("blah blah blah")),
+                            }
+                        ),
+                    ],
+                    ..Feed::new()
+                }),
+            ),
+        ]);
+
+        std::thread::sleep(std::time::Duration::from_millis(10)); // for distinct backup names
+        db.fetch_and_send_feeds(
+            Arc::new(Logger::new(LogLevel::Nothing)),
+            fetcher,
+            &RecorderSender::new(),
+            &FetchAndSendOptions::default(),
+        ).unwrap();
+        db.commit().unwrap();
+        assert_eq!(db.feed("http://example.com").unwrap().num_items(), 1);
+
+        // The newest backup is of the database as it was before the run.
+
+        let backups = db.backups().unwrap();
+        let mut backup = storage::open_read_only(&db.backup_dir().join(&backups[0])).unwrap();
+        assert_eq!(backup.load().unwrap()["http://example.com"].num_items(), 0);
+    }
+
+    #[test]
+    fn adding_a_feed_requires_it_to_not_exist() {
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let mut db = Database::create(&tdir.path().join("foo")).unwrap();
+        db.add_feed("https://xkcd.com/rss.xml").unwrap();
+    }
+
+    #[test]
+    fn removing_a_feed_requires_it_to_exist() {
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let mut db = Database::create(&tdir.path().join("foo")).unwrap();
+        db.remove_feed("https://xkcd.com/rss.xml").unwrap_err();
+        db.add_feed("https://xkcd.com/rss.xml").unwrap();
+        db.remove_feed("https://xkcd.com/rss.xml").unwrap();
+    }
+
+    #[test]
+    fn moving_a_feed_keeps_its_items() {
+
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let mut db = Database::create(&tdir.path().join("foo")).unwrap();
+        let item = |id: &str| {
+            (
+                String::from(id),
+                FeedItem {
+                    last_observed: Utc::now(),
+                    title: None,
+                    link: None,
+                    content: None,
+                },
+            )
+        };
+
+        db.add_feed("http://example.com/alpha").unwrap();
+        db.feeds.get_mut("http://example.com/alpha").unwrap().items = vec![item("one"), item("two")];
+        db.move_feed("http://example.com/alpha", "http://example.com/bravo", false).unwrap();
+        db.move_feed("http://example.com/alpha", "http://example.com/bravo", false).unwrap_err();
+        assert_eq!(db.feed_urls().collect::<Vec<_>>(), vec!["http://example.com/bravo"]);
+        assert_eq!(db.feed("http://example.com/bravo").unwrap().num_items(), 2);
+
+        db.add_feed("http://example.com/charlie").unwrap();
+        db.feeds.get_mut("http://example.com/charlie").unwrap().items = vec![item("two"), item("three")];
+        db.move_feed("http://example.com/charlie", "http://example.com/bravo", false).unwrap_err();
+        db.move_feed("http://example.com/charlie", "http://example.com/bravo", true).unwrap();
+        assert_eq!(db.feed_urls().collect::<Vec<_>>(), vec!["http://example.com/bravo"]);
+        assert_eq!(
+            db.feeds["http://example.com/bravo"]
+                .items
+                .iter()
+                .map(|&(ref id, _)| id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["one", "two", "three"]
+        );
+    }
+
+    #[test]
+    fn pruning_removes_only_stale_absent_items() {
+
+        let now = Utc::now();
+        let item = |id: &str, age_days: i64| {
+            (
+                String::from(id),
+                FeedItem {
+                    last_observed: now - Duration::days(age_days),
+                    title: None,
+                    link: None,
+                    content: None,
+                },
+            )
+        };
+
+        let mut feed = Feed {
+            title: Some(String::from("Example")),
+            items: vec![item("present", 100), item("stale", 100), item("recent", 1)],
+            ..Feed::new()
+        };
+
+        let present = vec![String::from("present")].into_iter().collect();
+        let mut policy = RetentionPolicy::new();
+        policy.with_max_age(Some(Duration::days(30)));
+        assert_eq!(feed.prune(&policy, now, &present), 1);
+        assert_eq!(
+            feed.items.iter().map(|&(ref id, _)| id.as_str()).collect::<Vec<_>>(),
+            vec!["present", "recent"]
+        );
+
+        let mut policy = RetentionPolicy::new();
+        policy.with_max_items(Some(1));
+        assert_eq!(feed.prune(&policy, now, &present), 1);
+        assert_eq!(
+            feed.items.iter().map(|&(ref id, _)| id.as_str()).collect::<Vec<_>>(),
+            vec!["present"]
+        );
+    }
+
+    #[test]
+    fn sent_items_survive_a_missing_commit() {
+
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let db_path = tdir.path().join("foo");
+        let mut db = Database::create(&db_path).unwrap();
+        db.add_feed("http://example.com").unwrap();
+        db.commit().unwrap();
+        let logger = Arc::new(Logger::new(LogLevel::Nothing));
+
+        let fetcher = MockFetcher::from(vec![
+            (
+                String::from("http://example.com"),
+                Ok(Feed {
+                    title: Some(String::from("Example")),
+                    items: vec![
+                        (
+                            String::from("id alpha"),
+                            FeedItem {
+                                last_observed: DateTime::from(SystemTime::now()),
+                                title: Some(String::from("entry alpha")),
+                                link: Some(String::from("http://example.com/alpha")),
+                                content: Some(String::from("blah blah blah")),
+                            }
+                        ),
+                    ],
+                    ..Feed::new()
+                }),
+            ),
+        ]);
+
+        let sender = RecorderSender::new();
+        db.fetch_and_send_feeds(
+            logger.clone(),
+            fetcher.clone(),
+            &sender,
+            &FetchAndSendOptions::default(),
+        ).unwrap();
+        assert_eq!(sender.recorded_items().len(), 1);
+        drop(db); // simulate a crash before committing
+
+        let mut db = Database::open(&db_path).unwrap();
+        let sender = RecorderSender::new();
+        db.fetch_and_send_feeds(
+            logger.clone(),
+            fetcher.clone(),
+            &sender,
+            &FetchAndSendOptions::default(),
+        ).unwrap();
+        assert_eq!(sender.recorded_items(), &[]);
+
+        db.commit().unwrap();
+        assert!(!storage::sibling_path(&db_path, "journal").exists());
+    }
+
+    #[test]
+    fn fetch_failures_are_recorded_per_feed() {
+
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let mut db = Database::create(&tdir.path().join("foo")).unwrap();
+        db.add_feed("http://example.com/alpha").unwrap();
+        db.add_feed("http://example.com/bravo").unwrap();
+        let logger = Arc::new(Logger::new(LogLevel::Nothing));
+
+        let fetcher = MockFetcher::from(vec![
+            (
+                String::from("http://example.com/alpha"),
+                Err(String::from("connection refused")),
+            ),
+            (String::from("http://example.com/bravo"), Ok(Feed::new())),
+        ]);
+
+        for _ in 0..2 {
+            let report = db.fetch_and_send_feeds(
+                logger.clone(),
+                fetcher.clone(),
+                &RecorderSender::new(),
+                &FetchAndSendOptions::default(),
+            ).unwrap();
+            assert_eq!(report.num_fetched(), 1);
+            assert_eq!(
+                report.failures(),
+                &[(String::from("http://example.com/alpha"), String::from("connection refused"))]
+            );
         }
+
+        let alpha = db.feed("http://example.com/alpha").unwrap();
+        assert!(alpha.added_at().is_some());
+        assert!(alpha.last_attempt().is_some());
+        assert_eq!(alpha.last_success(), None);
+        assert_eq!(alpha.last_error(), Some("connection refused"));
+        assert_eq!(alpha.consecutive_failures(), 2);
+
+        let bravo = db.feed("http://example.com/bravo").unwrap();
+        assert_eq!(bravo.last_success(), bravo.last_attempt());
+        assert_eq!(bravo.last_error(), None);
+        assert_eq!(bravo.consecutive_failures(), 0);
+        assert_eq!(bravo.last_http_status(), Some(200));
+
+        db.feed("http://example.com/charlie").unwrap_err();
+    }
+
+    #[test]
+    fn local_files_and_documents_are_fetched() {
+
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let db_path = tdir.path().join("foo");
+        let mut db = Database::create(&db_path).unwrap();
+        let logger = Arc::new(Logger::new(LogLevel::Nothing));
+
+        let document = "<?xml version=\"1.0\"?>\
+                        <rss version=\"2.0\"><channel><title>Local</title><link>http://example.com</link>\
+                        <description>Local feed</description>\
+                        <item><guid>id alpha</guid><title>entry alpha</title></item>\
+                        </channel></rss>";
+
+        let path = tdir.path().join("feed.xml");
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(document.as_bytes())
+            .unwrap();
+        let file_url = reqwest::Url::from_file_path(&path).unwrap().to_string();
+        let canonical_path = std::fs::canonicalize(&path).unwrap();
+        let plain_path = canonical_path.to_str().unwrap();
+        db.add_feed(&file_url).unwrap();
+
+        // A path is stored in its canonical form.
+
+        db.add_feed(tdir.path().join(".").join("feed.xml").to_str().unwrap()).unwrap();
+        db.add_feed(plain_path).unwrap_err();
+        assert!(db.feed(plain_path).is_ok());
+
+        let fetcher = NetFetcher::new(&NetFetcherOptions::new()).unwrap();
+        let report = db.fetch_and_send_feeds(
+            logger.clone(),
+            fetcher,
+            &RecorderSender::new(),
+            &FetchAndSendOptions::default(),
+        ).unwrap();
+        assert!(report.failures().is_empty());
+        assert_eq!(db.feed(&file_url).unwrap().num_items(), 1);
+        assert_eq!(db.feed(plain_path).unwrap().title(), Some("Local"));
+
+        db.add_feed("http://example.com").unwrap();
+        let mut options = FetchAndSendOptions::new();
+        options.with_feed_urls(vec!["http://example.com"]);
+        db.fetch_and_send_feeds(
+            logger.clone(),
+            DocumentFetcher::new(document.as_bytes().to_vec()),
+            &RecorderSender::new(),
+            &options,
+        ).unwrap();
+        assert_eq!(db.feed("http://example.com").unwrap().num_items(), 1);
+
+        // Nor are paths invalid feed URLs.
+
+        db.commit().unwrap();
+        drop(db);
+        assert_eq!(
+            Database::check(&db_path, &DatabaseConfig::default(), false).unwrap(),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn feeds_that_are_not_due_are_skipped_unless_forced() {
+
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let mut db = Database::create(&tdir.path().join("foo")).unwrap();
+        let logger = Arc::new(Logger::new(LogLevel::Nothing));
+
+        let document = "<rss version=\"2.0\"><channel><title>Example</title><link>http://example.com</link>\
+                        <description>Example</description><ttl>60</ttl>\
+                        <item><guid>id alpha</guid><title>entry alpha</title></item>\
+                        </channel></rss>";
+
+        db.add_feed("http://example.com").unwrap();
+        db.fetch_and_send_feeds(
+            logger.clone(),
+            DocumentFetcher::new(document.as_bytes().to_vec()),
+            &RecorderSender::new(),
+            &FetchAndSendOptions::default(),
+        ).unwrap();
+        let next_due = db.feed("http://example.com").unwrap().next_due().unwrap();
+        assert!(Utc::now() + Duration::minutes(59) < next_due);
+
+        db.feeds.get_mut("http://example.com").unwrap().items.clear();
+        db.fetch_and_send_feeds(
+            logger.clone(),
+            DocumentFetcher::new(document.as_bytes().to_vec()),
+            &RecorderSender::new(),
+            &FetchAndSendOptions::default(),
+        ).unwrap();
+        assert_eq!(db.feed("http://example.com").unwrap().num_items(), 0);
+
+        let mut options = FetchAndSendOptions::new();
+        options.with_force(true);
+        db.fetch_and_send_feeds(
+            logger.clone(),
+            DocumentFetcher::new(document.as_bytes().to_vec()),
+            &RecorderSender::new(),
+            &options,
+        ).unwrap();
+        assert_eq!(db.feed("http://example.com").unwrap().num_items(), 1);
     }
 
-    let raw = atom_syndication::Feed::from_str(body).map_err(|e| {
-        Error::new(format!("Failed to parse feed (feed URL: {})", feed_url))
-            .with_cause(e)
-            .into_error()
-    })?;
+    #[test]
+    fn gone_and_long_failing_feeds_are_disabled() {
 
-    Ok(Feed {
-        title: Some(String::from(raw.title())),
-        items: raw.entries()
-            .iter()
-            .map(|entry| {
-                (
-                    String::from(entry.id()),
-                    FeedItem {
-                        last_observed: DateTime::from(SystemTime::now()),
-                        title: Some(String::from(entry.title())),
-                        link: entry.links().first().map(|x| String::from(x.href())),
-                        content: entry.content().and_then(|x| x.value()).map(
-                            |x| String::from(x),
-                        ),
-                    },
-                )
-            })
-            .collect(),
-    })
-}
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let mut db = Database::create(&tdir.path().join("foo")).unwrap();
+        db.add_feed("http://alpha.example.com").unwrap();
+        db.add_feed("http://bravo.example.com").unwrap();
+        db.add_feed("http://charlie.example.com").unwrap();
+        let logger = Arc::new(Logger::new(LogLevel::Nothing));
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempdir::TempDir;
+        db.feeds.get_mut("http://bravo.example.com").unwrap().failing_since = Some(Utc::now() - Duration::days(30));
+        db.feeds.get_mut("http://charlie.example.com").unwrap().failing_since = Some(Utc::now() - Duration::days(3));
 
-    const TEST_PATH_PREFIX: &str = "rss2email";
+        let mut fetcher = MockFetcher::from(vec![
+            (String::from("http://alpha.example.com"), Err(String::from("gone"))),
+            (String::from("http://bravo.example.com"), Err(String::from("down"))),
+            (String::from("http://charlie.example.com"), Err(String::from("down"))),
+        ]);
+        fetcher.mock_outcomes[0].http_status = Some(410);
 
-    #[derive(Debug)]
-    pub struct RecorderSender {
-        recorded_items: Mutex<Vec<(String, String)>>,
-    }
+        db.fetch_and_send_feeds(
+            logger.clone(),
+            fetcher,
+            &RecorderSender::new(),
+            &FetchAndSendOptions::default(),
+        ).unwrap();
 
-    impl RecorderSender {
-        pub fn new() -> Self {
-            RecorderSender { recorded_items: Mutex::new(Vec::new()) }
-        }
+        assert!(db.feed("http://alpha.example.com").unwrap().disabled_reason().unwrap().contains("410"));
+        assert!(db.feed("http://bravo.example.com").unwrap().disabled_at().is_some());
+        assert_eq!(db.feed("http://charlie.example.com").unwrap().disabled_at(), None);
 
-        pub fn recorded_items(self) -> Vec<(String, String)> {
-            self.recorded_items.into_inner().unwrap()
-        }
-    }
+        let document = "<rss version=\"2.0\"><channel><title>Example</title><link>http://example.com</link>\
+                        <description>Example</description>\
+                        <item><guid>id alpha</guid><title>entry alpha</title></item>\
+                        </channel></rss>";
 
-    impl Sender for RecorderSender {
-        fn send(&self, feed_url: &str, _feed: &Feed, feed_item_id: &str, _feed_item: &FeedItem) -> Result<(), Error> {
-            self.recorded_items.lock().unwrap().push((
-                String::from(feed_url),
-                String::from(
-                    feed_item_id,
-                ),
-            ));
-            Ok(())
-        }
-    }
+        db.disable_feed("http://charlie.example.com").unwrap();
+        db.enable_feed("http://alpha.example.com").unwrap();
+        assert_eq!(db.feed("http://alpha.example.com").unwrap().failing_since(), None);
 
-    #[derive(Clone, Debug)]
-    pub struct MockFetcher {
-        mock_items: Vec<Result<(String, Feed), String>>,
+        let mut options = FetchAndSendOptions::new();
+        options.with_force(true);
+        db.fetch_and_send_feeds(
+            logger.clone(),
+            DocumentFetcher::new(document.as_bytes().to_vec()),
+            &RecorderSender::new(),
+            &options,
+        ).unwrap();
+
+        assert_eq!(db.feed("http://alpha.example.com").unwrap().num_items(), 1);
+        assert_eq!(db.feed("http://bravo.example.com").unwrap().num_items(), 0);
+        assert_eq!(db.feed("http://charlie.example.com").unwrap().num_items(), 0);
+        assert_eq!(db.feed("http://charlie.example.com").unwrap().disabled_reason(), None);
     }
 
-    impl Fetcher for MockFetcher {
-        type Stream = futures::stream::Iter<std::vec::IntoIter<Result<(String, Feed), Error>>>;
-        fn fetch(self, _logger: Arc<Logger>, _feed_urls: Vec<String>) -> Self::Stream {
+    #[test]
+    fn transcoded_feeds_are_not_decoded_twice() {
+
+        let body = b"<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?>\
+                     <rss version=\"2.0\"><channel><title>Caf\xe9</title><link>http://example.com</link>\
+                     <description>Example</description>\
+                     <item><title>Cr\xe8me br\xfbl\xe9e</title><guid>alpha</guid></item>\
+                     </channel></rss>";
+
+        let body = charset::decode(body, Some("application/rss+xml"), None).unwrap();
+        let feed = parse_syndication("http://example.com", &body).unwrap();
+        assert_eq!(feed.title(), Some("Caf\u{e9}"));
+        assert_eq!(feed.items[0].1.title, Some(String::from("Cr\u{e8}me br\u{fb}l\u{e9}e")));
+    }
 
-            let items = self.mock_items
-                .into_iter()
-                .map(|x| match x {
-                    Err(s) => Err(Error::new(s).into_error()),
-                    Ok(x) => Ok(x),
-                })
-                .collect::<Vec<_>>();
+    #[test]
+    fn json_feeds_are_parsed() {
+
+        let body = r#"{
+            "version": "https://jsonfeed.org/version/1",
+            "title": "Example",
+            "items": [
+                {"id": "alpha", "url": "http://example.com/alpha", "content_html": "<p>blah</p>"},
+                {"id": 2, "title": "entry bravo", "content_text": "blah blah"}
+            ]
+        }"#;
 
-            futures::stream::iter(items)
-        }
+        let feed = parse_syndication("http://example.com/feed.json", body).unwrap();
+        assert_eq!(feed.title(), Some("Example"));
+        assert_eq!(
+            feed.items.iter().map(|&(ref id, _)| id.as_str()).collect::<Vec<_>>(),
+            vec!["alpha", "2"]
+        );
+        assert_eq!(feed.items[0].1.content, Some(String::from("<p>blah</p>")));
+        assert_eq!(feed.items[1].1.title, Some(String::from("entry bravo")));
     }
 
-    impl From<Vec<Result<(String, Feed), String>>> for MockFetcher {
-        fn from(mock_items: Vec<Result<(String, Feed), String>>) -> Self {
-            MockFetcher { mock_items: mock_items }
+    #[test]
+    fn feeds_are_discovered_through_the_pages_linking_to_them() {
+
+        // The server redirects to the page, serves the page, then serves the
+        // feed that the page links to, each over a connection of its own.
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let page = "<html><head><link rel=\"alternate\" type=\"application/rss+xml\" \
+                    title=\"Posts\" href=\"rss.xml\"></head></html>";
+        let feed = "<rss version=\"2.0\"><channel><title>Example</title><link>http://example.com</link>\
+                    <description>Example</description></channel></rss>";
+        let response = |head: &str, body: &str| {
+            format!(
+                "HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+                head,
+                body.len(),
+                body
+            )
+        };
+        let responses = vec![
+            response("301 Moved Permanently\r\nLocation: /blog/", ""),
+            response("200 OK\r\nContent-Type: text/html", page),
+            response("200 OK\r\nContent-Type: application/rss+xml", feed),
+        ];
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+                requests.push(String::from_utf8_lossy(&request).to_lowercase());
+            }
+            requests
+        });
+
+        let logger = Logger::new(LogLevel::Nothing);
+        let fetcher = NetFetcher::new(&NetFetcherOptions::new()).unwrap();
+        let page_url = format!("http://127.0.0.1:{}/", port);
+        let mut config = FeedConfig::default();
+        config.bearer_token = Some(Secret::Value(String::from("t0ken")));
+
+        let links = match fetcher.discover(&logger, &page_url, Some(&config)).unwrap() {
+            Discovery::Page(x) => x,
+            x => panic!("Got {:?}", x),
+        };
+        assert_eq!(
+            links.iter().map(|x| x.url()).collect::<Vec<_>>(),
+            vec![format!("http://127.0.0.1:{}/blog/rss.xml", port)]
+        );
+        assert_eq!(links[0].title(), Some("Posts"));
+
+        match fetcher.discover(&logger, links[0].url(), None).unwrap() {
+            Discovery::Feed(title) => assert_eq!(title, Some(String::from("Example"))),
+            x => panic!("Got {:?}", x),
         }
+
+        // The page's settings apply to the page, as they would to a feed.
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("get / "));
+        assert!(requests[0].contains("authorization: bearer t0ken\r\n"));
+        assert!(requests[1].starts_with("get /blog/ "));
+        assert!(requests[1].contains("authorization: bearer t0ken\r\n"));
+        assert!(requests[2].starts_with("get /blog/rss.xml "));
+        assert!(!requests[2].contains("authorization:"));
     }
 
     #[test]
-    fn rss_content_is_in_description() {
+    fn unmodified_feeds_keep_their_items_and_validators() {
 
-        let source = r#"<rss version="2.0">
-<channel>
-<title>alpha</title>
-<link>http://bravo</link>
-<description>charlie</description>
-<item>
-<title>delta</title>
-<link>http://echo</link>
-<description>foxtrot</description>
-<guid>golf</guid>
-</item>
-</channel>
-</rss>"#;
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let mut db = Database::create(&tdir.path().join("foo")).unwrap();
+        db.add_feed("http://example.com").unwrap();
+        let logger = Arc::new(Logger::new(LogLevel::Nothing));
 
-        let got = super::parse_syndication("http://example.com", source).unwrap();
+        let mut fetcher = MockFetcher::from(vec![
+            (
+                String::from("http://example.com"),
+                Ok(Feed {
+                    title: Some(String::from("Example")),
+                    items: vec![
+                        (
+                            String::from("id alpha"),
+                            FeedItem {
+                                last_observed: DateTime::from(SystemTime::now()),
+                                title: Some(String::from("entry alpha")),
+                                link: Some(String::from("http://example.com/alpha")),
+                                content: Some(String::from("blah blah blah")),
+                            }
+                        ),
+                    ],
+                    ..Feed::new()
+                }),
+            ),
+        ]);
+        fetcher.mock_outcomes[0].etag = Some(String::from("\"v1\""));
 
-        let expected = Feed {
-            title: Some(String::from("alpha")),
-            items: vec![
-                (
-                    String::from("golf"),
-                    FeedItem {
-                        last_observed: got.items[0].1.last_observed,
-                        title: Some(String::from("delta")),
-                        link: Some(String::from("http://echo")),
-                        content: Some(String::from("foxtrot")),
-                    }
-                ),
-            ].into_iter()
-                .collect(),
+        db.fetch_and_send_feeds(
+            logger.clone(),
+            fetcher,
+            &RecorderSender::new(),
+            &FetchAndSendOptions::default(),
+        ).unwrap();
+
+        let request = FetchRequest::new("http://example.com", &db.feeds["http://example.com"], None);
+        assert_eq!(request.etag, Some(String::from("\"v1\"")));
+        assert_eq!(request.last_modified, None);
+
+        let fetcher = MockFetcher {
+            mock_outcomes: vec![
+                FetchOutcome {
+                    feed_url: String::from("http://example.com"),
+                    http_status: Some(304),
+                    etag: None,
+                    last_modified: None,
+                    redirects: Vec::new(),
+                    cookies: CookieJar::new(),
+                    max_age: None,
+                    result: Ok(None),
+                },
+            ],
         };
 
-        assert_eq!(got, expected);
+        let sender = RecorderSender::new();
+        db.fetch_and_send_feeds(
+            logger.clone(),
+            fetcher,
+            &sender,
+            &FetchAndSendOptions::default(),
+        ).unwrap();
+        assert_eq!(sender.recorded_items(), &[]);
+
+        let feed = db.feed("http://example.com").unwrap();
+        assert_eq!(feed.num_items(), 1);
+        assert_eq!(feed.last_http_status(), Some(304));
+        assert_eq!(feed.consecutive_failures(), 0);
+        assert_eq!(feed.etag, Some(String::from("\"v1\"")));
     }
 
     #[test]
-    fn creating_a_database_requires_it_to_not_exist() {
+    fn validators_are_saved_only_once_new_items_are_sent() {
+
         let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
-        let db_path = tdir.path().join("foo");
-        Database::create(&db_path).unwrap().commit().unwrap();
-        Database::create(&db_path).unwrap_err();
+        let mut db = Database::create(&tdir.path().join("foo")).unwrap();
+        db.add_feed("http://example.com").unwrap();
+        let logger = Arc::new(Logger::new(LogLevel::Nothing));
+
+        let mut fetcher = MockFetcher::from(vec![
+            (
+                String::from("http://example.com"),
+                Ok(Feed {
+                    title: Some(String::from("Example")),
+                    items: vec![
+                        (
+                            String::from("id alpha"),
+                            FeedItem {
+                                last_observed: DateTime::from(SystemTime::now()),
+                                title: Some(String::from("entry alpha")),
+                                link: Some(String::from("http://example.com/alpha")),
+                                content: Some(String::from("blah blah blah")),
+                            }
+                        ),
+                    ],
+                    ..Feed::new()
+                }),
+            ),
+        ]);
+        fetcher.mock_outcomes[0].etag = Some(String::from("\"v1\""));
+        fetcher.mock_outcomes[0].last_modified = Some(String::from("Sat, 01 Jul 2017 00:00:00 GMT"));
+        fetcher.mock_outcomes[0].max_age = Some(Duration::days(1));
+
+        db.fetch_and_send_feeds(
+            logger.clone(),
+            fetcher.clone(),
+            &FailingSender,
+            &FetchAndSendOptions::default(),
+        ).unwrap();
+
+        // The item wasn't sent, so the next fetch, which is due at once, must
+        // get it again.
+
+        let request = FetchRequest::new("http://example.com", &db.feeds["http://example.com"], None);
+        assert_eq!(request.etag, None);
+        assert_eq!(request.last_modified, None);
+        assert_eq!(db.feed("http://example.com").unwrap().num_items(), 0);
+        assert_eq!(db.feeds["http://example.com"].next_due, None);
+
+        let sender = RecorderSender::new();
+        db.fetch_and_send_feeds(
+            logger.clone(),
+            fetcher,
+            &sender,
+            &FetchAndSendOptions::default(),
+        ).unwrap();
+        assert_eq!(
+            sender.recorded_items(),
+            vec![(String::from("http://example.com"), String::from("id alpha"))]
+        );
+
+        let request = FetchRequest::new("http://example.com", &db.feeds["http://example.com"], None);
+        assert_eq!(request.etag, Some(String::from("\"v1\"")));
+        assert_eq!(request.last_modified, Some(String::from("Sat, 01 Jul 2017 00:00:00 GMT")));
+        assert!(db.feeds["http://example.com"].next_due.is_some());
     }
 
     #[test]
-    fn opening_a_database_requires_it_to_exist() {
+    fn permanently_redirected_feeds_are_moved() {
+
         let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
-        let db_path = tdir.path().join("foo");
-        Database::open(&db_path).unwrap_err();
-        Database::create(&db_path).unwrap().commit().unwrap();
-        Database::open(&db_path).unwrap();
+        let mut db = Database::create(&tdir.path().join("foo")).unwrap();
+        db.add_feed("http://example.com/alpha").unwrap();
+        let logger = Arc::new(Logger::new(LogLevel::Nothing));
+
+        let mut fetcher = MockFetcher::from(vec![
+            (String::from("http://example.com/alpha"), Ok(Feed::new())),
+        ]);
+        fetcher.mock_outcomes[0].redirects = vec![
+            (301, String::from("http://example.com/bravo")),
+            (302, String::from("http://example.com/charlie")),
+        ];
+
+        let mut options = FetchAndSendOptions::new();
+        options.with_permanent_redirect_runs(2);
+
+        db.fetch_and_send_feeds(logger.clone(), fetcher.clone(), &RecorderSender::new(), &options)
+            .unwrap();
+        assert_eq!(db.feed_urls().collect::<Vec<_>>(), vec!["http://example.com/alpha"]);
+        assert_eq!(
+            db.feed("http://example.com/alpha").unwrap().permanent_redirect(),
+            Some("http://example.com/bravo")
+        );
+
+        db.fetch_and_send_feeds(logger.clone(), fetcher.clone(), &RecorderSender::new(), &options)
+            .unwrap();
+        assert_eq!(db.feed_urls().collect::<Vec<_>>(), vec!["http://example.com/bravo"]);
     }
 
     #[test]
-    fn adding_a_feed_requires_it_to_not_exist() {
-        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
-        let mut db = Database::create(&tdir.path().join("foo")).unwrap();
-        db.add_feed("https://xkcd.com/rss.xml").unwrap();
+    fn retries_stop_at_the_limit_or_the_deadline() {
+
+        let retry = RetryBudget {
+            max_retries: 2,
+            deadline: std::time::Instant::now() + std::time::Duration::from_secs(60),
+        };
+        assert!(retry.delay(0, None).unwrap() <= std::time::Duration::from_millis(RETRY_BASE_DELAY_MILLIS));
+        assert!(retry.delay(1, None).is_some());
+        assert_eq!(retry.delay(2, None), None);
+        assert_eq!(
+            retry.delay(0, Some(std::time::Duration::from_secs(5))),
+            Some(std::time::Duration::from_secs(5))
+        );
+        assert_eq!(retry.delay(0, Some(std::time::Duration::from_secs(3600))), None);
+
+        let mut headers = reqwest::header::Headers::new();
+        headers.set_raw("Retry-After", "120");
+        assert_eq!(parse_retry_after(&headers), Some(std::time::Duration::from_secs(120)));
+        headers.set_raw("Retry-After", "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_retry_after(&headers), Some(std::time::Duration::from_secs(0)));
     }
 
     #[test]
-    fn removing_a_feed_requires_it_to_exist() {
-        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
-        let mut db = Database::create(&tdir.path().join("foo")).unwrap();
-        db.remove_feed("https://xkcd.com/rss.xml").unwrap_err();
-        db.add_feed("https://xkcd.com/rss.xml").unwrap();
-        db.remove_feed("https://xkcd.com/rss.xml").unwrap();
+    fn only_connect_errors_and_timeouts_are_retried() {
+
+        let client = reqwest::Client::new();
+
+        // Nothing listens on a port just released.
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let e = client.get(&format!("http://127.0.0.1:{}/", port)).send().unwrap_err();
+        assert!(is_transient_error(&e));
+
+        // A server that responds with garbage will do so again.
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(b"garbage\r\n\r\n").unwrap();
+        });
+        let e = client.get(&format!("http://127.0.0.1:{}/", port)).send().unwrap_err();
+        assert!(!is_transient_error(&e));
+        server.join().unwrap();
+
+        assert!(is_transient_io_error(std::io::ErrorKind::TimedOut));
+        assert!(!is_transient_io_error(std::io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn feed_credentials_are_sent_only_to_the_feeds_origin() {
+
+        let mut config = FeedConfig::default();
+        config.username = Some(String::from("alice"));
+        config.password = Some(Secret::Value(String::from("secret")));
+        config.headers.insert(String::from("X-Token"), Secret::Value(String::from("token")));
+
+        let mut common = reqwest::header::Headers::new();
+        common.set_raw("User-Agent", "rss2email");
+
+        let headers = feed_headers(&config, &common).unwrap();
+        assert_eq!(
+            raw_header(&headers, "Authorization"),
+            Some(String::from("Basic YWxpY2U6c2VjcmV0"))
+        );
+        assert_eq!(raw_header(&headers, "X-Token"), Some(String::from("token")));
+        assert_eq!(raw_header(&headers, "User-Agent"), Some(String::from("rss2email")));
+        assert_eq!(raw_header(&common, "Authorization"), None);
+
+        config.bearer_token = Some(Secret::Value(String::from("t0ken")));
+        let headers = feed_headers(&config, &common).unwrap();
+        assert_eq!(raw_header(&headers, "Authorization"), Some(String::from("Bearer t0ken")));
+
+        let url = |x: &str| reqwest::Url::parse(x).unwrap();
+        let own_url = url("https://example.com/feed.xml");
+        assert!(same_origin(&url("https://example.com:443/other.xml"), &own_url));
+        assert!(!same_origin(&url("http://example.com/feed.xml"), &own_url));
+        assert!(!same_origin(&url("https://example.com:8443/feed.xml"), &own_url));
+        assert!(!same_origin(&url("https://www.example.com/feed.xml"), &own_url));
+    }
+
+    #[test]
+    fn bodies_are_decompressed_and_limited_in_size() {
+
+        let body = b"hello, world";
+
+        assert_eq!(read_limited(&body[..], 12).unwrap(), body);
+        read_limited(&body[..], 11).unwrap_err();
+        assert_eq!(read_limited(&body[..], u64::max_value()).unwrap(), body);
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(body).unwrap();
+        let gzip = gzip.finish().unwrap();
+
+        let mut deflate = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        deflate.write_all(body).unwrap();
+        let deflate = deflate.finish().unwrap();
+
+        // A Brotli stream of a single uncompressed meta-block
+        let mut br = vec![0xb0, 0x00, 0x10];
+        br.extend_from_slice(body);
+        br.push(0x03);
+
+        for &(encoding, ref encoded) in &[("gzip", gzip), ("deflate", deflate), ("br", br)] {
+            let reader = decoding_reader(&encoded[..], Some(encoding)).unwrap();
+            assert_eq!(read_limited(reader, 12).unwrap(), body, "encoding: {}", encoding);
+            let reader = decoding_reader(&encoded[..], Some(encoding)).unwrap();
+            read_limited(reader, 11).unwrap_err(); // the limit applies to the decompressed body
+        }
+
+        assert!(decoding_reader(&body[..], Some("compress")).is_err());
     }
 
     #[test]
@@ -764,9 +3464,9 @@ mod tests {
         let logger = Arc::new(Logger::new(LogLevel::Nothing));
 
         let fetcher = MockFetcher::from(vec![
-            Ok((
+            (
                 String::from("http://example.com"),
-                Feed {
+                Ok(Feed {
                     title: Some(String::from("Example")),
                     items: vec![
                         (
@@ -780,8 +3480,9 @@ mod tests {
                         ),
                     ].into_iter()
                         .collect(),
-                },
-            )),
+                    ..Feed::new()
+                }),
+            ),
         ]);
 
         let sender = RecorderSender::new();
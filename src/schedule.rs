@@ -0,0 +1,222 @@
+use {rss, std};
+use chrono::{DateTime, Datelike, Duration, Timelike, Utc};
+
+const SYNDICATION_NAMESPACE: &str = "http://purl.org/rss/1.0/modules/syndication/";
+const MAX_INTERVAL_DAYS: i64 = 7;
+const MAX_INTERVAL_MINUTES: i64 = MAX_INTERVAL_DAYS * 24 * 60;
+
+/// `Schedule` is what a feed document says about how often to fetch it.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Schedule {
+    #[serde(default)]
+    interval_minutes: Option<i64>, // from the RSS `ttl` or the Syndication module
+    #[serde(default)]
+    skip_hours: Vec<u32>, // UTC hours, 0 to 23
+    #[serde(default)]
+    skip_days: Vec<u32>, // days from Monday, 0 to 6
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Schedule::default()
+    }
+
+    /// Reads the RSS `ttl`, `skipHours` and `skipDays` elements, and the
+    /// Syndication module's `updatePeriod` and `updateFrequency` elements.
+    pub fn from_rss(channel: &rss::Channel) -> Self {
+
+        let ttl = channel
+            .ttl()
+            .and_then(|x| x.trim().parse::<i64>().ok())
+            .and_then(|x| if 0 < x { Some(x) } else { None });
+
+        Schedule {
+            interval_minutes: ttl.or_else(|| syndication_interval(channel)),
+            skip_hours: channel
+                .skip_hours()
+                .iter()
+                .filter_map(|x| x.trim().parse::<u32>().ok())
+                .map(|x| x % 24) // some feeds say 24 for midnight
+                .collect(),
+            skip_days: channel
+                .skip_days()
+                .iter()
+                .filter_map(|x| day_from_name(x.trim()))
+                .collect(),
+        }
+    }
+
+    /// Returns when a feed fetched at `now` is next due, or none if it's due
+    /// at every run.
+    ///
+    /// A configured interval takes precedence. Otherwise the feed is due after
+    /// the longest of the intervals the feed and the server's cache headers
+    /// give, but after no more than a week.
+    pub fn next_due(
+        &self,
+        now: DateTime<Utc>,
+        configured: Option<Duration>,
+        cache_max_age: Option<Duration>,
+    ) -> Option<DateTime<Utc>> {
+        let interval = match configured {
+            Some(x) => Some(x),
+            None => {
+                let hinted = self.interval_minutes.map(|x| Duration::minutes(clamp(x, MAX_INTERVAL_MINUTES)));
+                std::cmp::max(hinted, cache_max_age).map(|x| std::cmp::min(x, Duration::days(MAX_INTERVAL_DAYS)))
+            }
+        };
+        interval.map(|x| now + x)
+    }
+
+    /// Returns whether the feed asks not to be fetched at this time.
+    pub fn skips(&self, now: DateTime<Utc>) -> bool {
+        self.skip_hours.contains(&now.hour()) || self.skip_days.contains(&now.weekday().num_days_from_monday())
+    }
+}
+
+/// Returns an interval of the configured number of minutes, but of no more than
+/// a week.
+pub fn configured_interval(minutes: u64) -> Duration {
+    Duration::minutes(std::cmp::min(minutes, MAX_INTERVAL_MINUTES as u64) as i64)
+}
+
+/// Limits a number to the range from zero to `max`, so that a `Duration` made
+/// from it can't overflow.
+fn clamp(x: i64, max: i64) -> i64 {
+    std::cmp::max(0, std::cmp::min(x, max))
+}
+
+fn syndication_interval(channel: &rss::Channel) -> Option<i64> {
+
+    let prefix = channel
+        .namespaces()
+        .iter()
+        .find(|&(_, uri)| uri == SYNDICATION_NAMESPACE)
+        .map(|(prefix, _)| prefix.as_str())
+        .unwrap_or("sy");
+
+    let value = |name: &str| {
+        channel
+            .extensions()
+            .get(prefix)
+            .and_then(|x| x.get(name))
+            .and_then(|x| x.first())
+            .and_then(|x| x.value())
+            .map(|x| x.trim().to_lowercase())
+    };
+
+    let period_minutes = match value("updatePeriod").as_ref().map(|x| x.as_str()) {
+        None => return None,
+        Some("hourly") => 60,
+        Some("daily") => 24 * 60,
+        Some("weekly") => 7 * 24 * 60,
+        Some("monthly") => 30 * 24 * 60,
+        Some("yearly") => 365 * 24 * 60,
+        Some(_) => return None,
+    };
+
+    let frequency = value("updateFrequency")
+        .and_then(|x| x.parse::<i64>().ok())
+        .and_then(|x| if 0 < x { Some(x) } else { None })
+        .unwrap_or(1);
+
+    Some(std::cmp::max(period_minutes / frequency, 1))
+}
+
+fn day_from_name(name: &str) -> Option<u32> {
+    let days = ["monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday"];
+    days.iter().position(|x| x.eq_ignore_ascii_case(name)).map(|x| x as u32)
+}
+
+/// Returns how long a response stays fresh according to its `Cache-Control`
+/// `max-age` directive or, failing that, its `Expires` header.
+pub fn cache_max_age(cache_control: Option<&str>, expires: Option<&str>, now: DateTime<Utc>) -> Option<Duration> {
+
+    let max_age = cache_control.and_then(|x| {
+        x.split(',')
+            .filter_map(|directive| {
+                let mut parts = directive.splitn(2, '=');
+                let name = parts.next().unwrap().trim();
+                let value = parts.next()?.trim().trim_matches('"');
+                if name.eq_ignore_ascii_case("max-age") {
+                    value.parse::<i64>().ok()
+                } else {
+                    None
+                }
+            })
+            .next()
+    });
+
+    if let Some(x) = max_age {
+        return Some(Duration::seconds(clamp(x, MAX_INTERVAL_MINUTES * 60)));
+    }
+
+    expires
+        .and_then(|x| DateTime::parse_from_rfc2822(x.trim()).ok())
+        .map(|x| x.with_timezone(&Utc).signed_duration_since(now))
+        .and_then(|x| if Duration::zero() < x { Some(x) } else { None })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn feeds_are_due_after_the_longest_interval_given() {
+
+        let body = "<rss version=\"2.0\" xmlns:sy=\"http://purl.org/rss/1.0/modules/syndication/\">\
+                    <channel><title>Example</title><link>http://example.com</link>\
+                    <description>Example</description>\
+                    <sy:updatePeriod>daily</sy:updatePeriod><sy:updateFrequency>4</sy:updateFrequency>\
+                    <skipHours><hour>3</hour></skipHours><skipDays><day>Sunday</day></skipDays>\
+                    </channel></rss>";
+        let channel = rss::Channel::read_from(std::io::Cursor::new(body)).unwrap();
+        let schedule = Schedule::from_rss(&channel);
+
+        let now = Utc.ymd(2017, 7, 3).and_hms(12, 0, 0); // a Monday
+        assert_eq!(schedule.next_due(now, None, None), Some(now + Duration::hours(6)));
+        assert_eq!(
+            schedule.next_due(now, None, Some(Duration::days(30))),
+            Some(now + Duration::days(7))
+        );
+        assert_eq!(
+            schedule.next_due(now, Some(Duration::minutes(5)), Some(Duration::days(1))),
+            Some(now + Duration::minutes(5))
+        );
+        assert_eq!(Schedule::new().next_due(now, None, None), None);
+
+        assert!(!schedule.skips(now));
+        assert!(schedule.skips(Utc.ymd(2017, 7, 3).and_hms(3, 30, 0)));
+        assert!(schedule.skips(Utc.ymd(2017, 7, 9).and_hms(12, 0, 0)));
+
+        assert_eq!(
+            cache_max_age(Some("public, max-age=3600"), Some("Mon, 03 Jul 2017 18:00:00 GMT"), now),
+            Some(Duration::hours(1))
+        );
+        assert_eq!(
+            cache_max_age(None, Some("Mon, 03 Jul 2017 18:00:00 GMT"), now),
+            Some(Duration::hours(6))
+        );
+        assert_eq!(cache_max_age(Some("no-cache"), Some("0"), now), None);
+    }
+
+    #[test]
+    fn huge_intervals_are_limited_to_a_week() {
+
+        let body = "<rss version=\"2.0\"><channel><title>Example</title><link>http://example.com</link>\
+                    <description>Example</description><ttl>99999999999999</ttl></channel></rss>";
+        let channel = rss::Channel::read_from(std::io::Cursor::new(body)).unwrap();
+        let schedule = Schedule::from_rss(&channel);
+
+        let now = Utc.ymd(2017, 7, 3).and_hms(12, 0, 0);
+        assert_eq!(schedule.next_due(now, None, None), Some(now + Duration::days(7)));
+        assert_eq!(
+            cache_max_age(Some("max-age=99999999999999999"), None, now),
+            Some(Duration::days(7))
+        );
+        assert_eq!(cache_max_age(Some("max-age=-99999999999999999"), None, now), Some(Duration::zero()));
+        assert_eq!(configured_interval(u64::max_value()), Duration::days(7));
+        assert_eq!(configured_interval(90), Duration::minutes(90));
+    }
+}
@@ -0,0 +1,790 @@
+use {Error, FakeDebug, rmp_serde, rusqlite, serde_json, std};
+use config::StorageKind;
+use journal::{Journal, JournalEntry};
+use model::{Feed, FeedItem};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// `SCHEMA_VERSION` is the version of the on-disk database format that this
+/// program writes. Bump it whenever `Feed` or `FeedItem` change in a way that
+/// older databases can't be deserialized as-is, and add a migration.
+const SCHEMA_VERSION: u64 = 1;
+
+/// `MIGRATIONS[n]` upgrades a database from version `n` to version `n+1`.
+const MIGRATIONS: &[fn(serde_json::Value) -> Result<serde_json::Value, Error>] = &[migrate_v0_to_v1];
+
+/// `SQLITE_SCHEMA_VERSION` is the `user_version` of the SQLite databases that
+/// this program writes.
+const SQLITE_SCHEMA_VERSION: i64 = 1;
+
+const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+
+/// `MSGPACK_MAGIC` begins every MessagePack database file, to tell it apart
+/// from a JSON database file.
+const MSGPACK_MAGIC: &[u8] = b"rss2email msgpack\n";
+
+/// `Storage` is where a `Database` keeps its feeds between runs.
+pub trait Storage: std::fmt::Debug {
+    fn kind(&self) -> StorageKind;
+
+    /// Reads all feeds from storage without deserializing them, so that one
+    /// corrupt feed doesn't keep the others from being read.
+    fn load_raw(&mut self) -> Result<HashMap<String, serde_json::Value>, Error>;
+
+    /// Reads all feeds from storage.
+    fn load(&mut self) -> Result<HashMap<String, Feed>, Error> {
+        self.load_raw()?
+            .into_iter()
+            .map(|(feed_url, raw)| -> Result<(String, Feed), Error> {
+                let feed = serde_json::from_value(raw).map_err(|e| {
+                    Error::new(format!("Database is corrupt (feed URL: {})", feed_url))
+                        .with_cause(e)
+                        .into_error()
+                })?;
+                Ok((feed_url, feed))
+            })
+            .collect()
+    }
+
+    /// Durably records a sent item, so that the item survives even if the
+    /// process dies before the next commit.
+    fn record_item(&mut self, feed_url: &str, item_id: &str, item: &FeedItem) -> Result<(), Error>;
+
+    /// Atomically replaces the stored feeds with the given feeds.
+    fn commit(&mut self, feeds: &HashMap<String, Feed>) -> Result<(), Error>;
+}
+
+/// Creates new, empty storage of the given kind.
+pub fn create(path: &Path, kind: StorageKind) -> Result<Box<Storage>, Error> {
+    Ok(match kind {
+        StorageKind::Json => Box::new(FileStorage::new(path, FileFormat::Json)),
+        StorageKind::MessagePack => Box::new(FileStorage::new(path, FileFormat::MessagePack)),
+        StorageKind::Sqlite => Box::new(SqliteStorage::open(path)?),
+    })
+}
+
+/// Opens existing storage, detecting its kind from the file's content.
+pub fn open(path: &Path) -> Result<Box<Storage>, Error> {
+    open_with_mode(path, false)
+}
+
+/// Opens existing storage for loading only, such as a backup. Loading leaves
+/// the storage untouched: an old schema is upgraded in memory only, and no
+/// journal is replayed or created.
+pub fn open_read_only(path: &Path) -> Result<Box<Storage>, Error> {
+    open_with_mode(path, true)
+}
+
+fn open_with_mode(path: &Path, read_only: bool) -> Result<Box<Storage>, Error> {
+
+    let f = std::fs::File::open(path).map_err(|e| {
+        Error::new(format!("Failed to open database (path: {:?})", path))
+            .with_cause(e)
+            .into_error()
+    })?;
+
+    let mut header = Vec::new();
+    f.take(std::cmp::max(SQLITE_MAGIC.len(), MSGPACK_MAGIC.len()) as u64)
+        .read_to_end(&mut header)
+        .map_err(|e| {
+            Error::new(format!("Failed to read database (path: {:?})", path))
+                .with_cause(e)
+                .into_error()
+        })?;
+
+    Ok(if header.starts_with(SQLITE_MAGIC) {
+        if read_only {
+            Box::new(SqliteStorage::open_read_only(path)?)
+        } else {
+            Box::new(SqliteStorage::open(path)?)
+        }
+    } else {
+        let format = if header.starts_with(MSGPACK_MAGIC) {
+            FileFormat::MessagePack
+        } else {
+            FileFormat::Json
+        };
+        let mut storage = FileStorage::new(path, format);
+        storage.read_only = read_only;
+        Box::new(storage)
+    })
+}
+
+pub fn sibling_path(db_path: &Path, extension: &str) -> PathBuf {
+    let mut p = PathBuf::from(db_path);
+    p.set_extension(extension);
+    p
+}
+
+/// `FileStorage` keeps feeds in a single file that's rewritten on every
+/// commit, plus a journal of items sent since the last commit.
+#[derive(Debug)]
+pub struct FileStorage {
+    path: PathBuf,
+    format: FileFormat,
+    journal: Journal,
+    read_only: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FileFormat {
+    Json,
+
+    /// MessagePack, preceded by `MSGPACK_MAGIC`
+    MessagePack,
+}
+
+impl FileStorage {
+    pub fn new(path: &Path, format: FileFormat) -> Self {
+        FileStorage {
+            path: PathBuf::from(path),
+            format: format,
+            journal: Journal::new(sibling_path(path, "journal")),
+            read_only: false,
+        }
+    }
+}
+
+impl Storage for FileStorage {
+    fn kind(&self) -> StorageKind {
+        match self.format {
+            FileFormat::Json => StorageKind::Json,
+            FileFormat::MessagePack => StorageKind::MessagePack,
+        }
+    }
+
+    fn load_raw(&mut self) -> Result<HashMap<String, serde_json::Value>, Error> {
+
+        let path = &self.path;
+
+        let mut f = std::fs::File::open(path).map_err(|e| {
+            Error::new(format!("Failed to open database (path: {:?})", path))
+                .with_cause(e)
+                .into_error()
+        })?;
+
+        let mut content = Vec::new();
+        f.read_to_end(&mut content).map_err(|e| {
+            Error::new(format!("Failed to read database (path: {:?})", path))
+                .with_cause(e)
+                .into_error()
+        })?;
+
+        // Both formats decode to a JSON value so that they share migrations.
+
+        let raw = match self.format {
+            FileFormat::Json => serde_json::from_slice(&content).map_err(|e| corrupt_error(path, e))?,
+            FileFormat::MessagePack => {
+                if !content.starts_with(MSGPACK_MAGIC) {
+                    return Err(
+                        Error::new(format!(
+                            "Database is corrupt (path: {:?}): MessagePack header is missing",
+                            path
+                        )).into_error(),
+                    );
+                }
+                rmp_serde::from_slice(&content[MSGPACK_MAGIC.len()..]).map_err(|e| corrupt_error(path, e))?
+            }
+        };
+
+        let raw = migrate(path, raw, !self.read_only)?;
+
+        let RawDatabaseFile { mut feeds } = serde_json::from_value(raw).map_err(|e| corrupt_error(path, e))?;
+
+        if self.read_only {
+            return Ok(feeds);
+        }
+
+        // Fold in the items sent since the last commit, in case the process
+        // that sent them died before committing.
+
+        for entry in self.journal.replay()? {
+
+            let items = match feeds
+                .get_mut(&entry.feed_url)
+                .and_then(|x| x.get_mut("items"))
+                .and_then(|x| x.as_array_mut()) {
+                None => continue, // feed was since removed
+                Some(x) => x,
+            };
+
+            if items.iter().any(|x| {
+                x.get(0).and_then(|id| id.as_str()) == Some(entry.item_id.as_str())
+            })
+            {
+                continue; // journal outlived the commit that absorbed it
+            }
+
+            let item = serde_json::to_value(&entry.item).map_err(|e| corrupt_error(path, e))?;
+            items.push(serde_json::Value::Array(
+                vec![serde_json::Value::String(entry.item_id), item],
+            ));
+        }
+
+        Ok(feeds)
+    }
+
+    fn record_item(&mut self, feed_url: &str, item_id: &str, item: &FeedItem) -> Result<(), Error> {
+        if self.read_only {
+            return Err(read_only_error(&self.path));
+        }
+        self.journal.append(&JournalEntry {
+            feed_url: String::from(feed_url),
+            item_id: String::from(item_id),
+            item: item.clone(),
+        })
+    }
+
+    fn commit(&mut self, feeds: &HashMap<String, Feed>) -> Result<(), Error> {
+
+        if self.read_only {
+            return Err(read_only_error(&self.path));
+        }
+
+        // This employs the write-sync-rename pattern to guarantee an atomic
+        // update.
+
+        let working_path = sibling_path(&self.path, "working");
+
+        let mut f = std::fs::File::create(&working_path).map_err(|e| {
+            Error::new(format!(
+                "Failed to create replacement database (path: {:?})",
+                working_path
+            )).with_cause(e)
+                .into_error()
+        })?;
+
+        let content = DatabaseFileRef {
+            version: SCHEMA_VERSION,
+            feeds: feeds,
+        };
+
+        match self.format {
+            FileFormat::Json => {
+
+                serde_json::to_writer(f.by_ref(), &content).map_err(
+                    |e| {
+                        Error::new(format!(
+                            "Failed to write feeds to database (path: {:?})",
+                            working_path
+                        )).with_cause(e)
+                            .into_error()
+                    },
+                )?;
+
+                f.write_all(b"\n").map_err(|e| {
+                    Error::new(format!(
+                        "Failed to write trailing newline to database (path: {:?})",
+                        working_path
+                    )).with_cause(e)
+                        .into_error()
+                })?;
+            }
+            FileFormat::MessagePack => {
+
+                // Structs are encoded as maps, not positionally as arrays
+                // the way `rmp_serde::to_vec` would, so that the decoded JSON
+                // value has field names for migrations to use, and so that
+                // fields marked `#[serde(default)]` may be missing.
+
+                let encoded = rmp_serde::to_vec_named(&content).map_err(|e| {
+                    Error::new(format!(
+                        "Failed to encode feeds for database (path: {:?})",
+                        working_path
+                    )).with_cause(e)
+                        .into_error()
+                })?;
+
+                f.write_all(MSGPACK_MAGIC)
+                    .and_then(|_| f.write_all(&encoded))
+                    .map_err(|e| {
+                        Error::new(format!(
+                            "Failed to write feeds to database (path: {:?})",
+                            working_path
+                        )).with_cause(e)
+                            .into_error()
+                    })?;
+            }
+        }
+
+        f.sync_all().map_err(|e| {
+            Error::new(format!(
+                "Failed to sync database to disk (path: {:?})",
+                working_path
+            )).with_cause(e)
+                .into_error()
+        })?;
+
+        std::fs::rename(&working_path, &self.path).map_err(|e| {
+            Error::new(format!(
+                "Failed to replace database (destination: {:?}, source: {:?})",
+                self.path,
+                working_path
+            )).with_cause(e)
+                .into_error()
+        })?;
+
+        // Only now that the replacement database reflects every journaled
+        // item is it safe to discard the journal.
+
+        self.journal.clear()
+    }
+}
+
+/// `RawDatabaseFile` is the on-disk envelope of the database, as of version
+/// 1, with each feed left undeserialized.
+#[derive(Debug, Deserialize)]
+struct RawDatabaseFile {
+    feeds: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct DatabaseFileRef<'a> {
+    version: u64,
+    feeds: &'a HashMap<String, Feed>,
+}
+
+/// Returns the schema version of a raw database. Version 0 predates the
+/// envelope and is a bare map of feed URL to feed.
+fn schema_version(raw: &serde_json::Value) -> Option<u64> {
+    match raw.get("version") {
+        None => match *raw {
+            serde_json::Value::Object(..) => Some(0),
+            _ => None,
+        },
+        Some(v) => v.as_u64(),
+    }
+}
+
+/// Upgrades a raw database to the current schema version, first backing up
+/// the database file, if asked to, if it needs any upgrading.
+fn migrate(db_path: &Path, mut raw: serde_json::Value, back_up: bool) -> Result<serde_json::Value, Error> {
+
+    let version = schema_version(&raw).ok_or_else(|| {
+        Error::new(format!(
+            "Database is corrupt (path: {:?}): Cannot determine schema version",
+            db_path
+        )).into_error()
+    })?;
+
+    if SCHEMA_VERSION < version {
+        return Err(
+            Error::new(format!(
+                "Database was written by a newer version of {} (path: {:?}, database version: {}, supported \
+                 version: {}); please upgrade {}",
+                env!("CARGO_PKG_NAME"),
+                db_path,
+                version,
+                SCHEMA_VERSION,
+                env!("CARGO_PKG_NAME")
+            )).into_error(),
+        );
+    }
+
+    if version == SCHEMA_VERSION {
+        return Ok(raw);
+    }
+
+    if back_up {
+        let backup_path = sibling_path(db_path, &format!("v{}.backup", version));
+        std::fs::copy(db_path, &backup_path).map_err(|e| {
+            Error::new(format!(
+                "Failed to back up database before upgrading it (path: {:?}, backup path: {:?})",
+                db_path,
+                backup_path
+            )).with_cause(e)
+                .into_error()
+        })?;
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        raw = migration(raw).map_err(|e| {
+            Error::chain(
+                format!("Failed to upgrade database (path: {:?})", db_path),
+                e,
+            ).into_error()
+        })?;
+    }
+
+    Ok(raw)
+}
+
+fn migrate_v0_to_v1(raw: serde_json::Value) -> Result<serde_json::Value, Error> {
+    let mut m = serde_json::Map::new();
+    m.insert(String::from("version"), serde_json::Value::from(1));
+    m.insert(String::from("feeds"), raw);
+    Ok(serde_json::Value::Object(m))
+}
+
+/// `SqliteStorage` keeps feeds in an SQLite database, one row per feed and one
+/// row per item, so that recording a sent item is a small transaction of its
+/// own.
+///
+/// Feeds and items are stored as JSON text, which spares us an SQL schema
+/// change whenever `Feed` or `FeedItem` gains a field.
+#[derive(Debug)]
+pub struct SqliteStorage {
+    path: PathBuf,
+    conn: FakeDebug<rusqlite::Connection>,
+}
+
+impl SqliteStorage {
+    /// Opens the SQLite database, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+
+        let conn = rusqlite::Connection::open(path).map_err(|e| sqlite_error(path, e))?;
+        check_sqlite_version(path, &conn)?;
+
+        conn.execute_batch(&format!(
+            "BEGIN;
+             CREATE TABLE IF NOT EXISTS feeds (
+                 url TEXT PRIMARY KEY NOT NULL,
+                 data TEXT NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS items (
+                 feed_url TEXT NOT NULL,
+                 item_id TEXT NOT NULL,
+                 data TEXT NOT NULL,
+                 PRIMARY KEY (feed_url, item_id)
+             );
+             PRAGMA user_version = {};
+             COMMIT;",
+            SQLITE_SCHEMA_VERSION
+        )).map_err(|e| sqlite_error(path, e))?;
+
+        Ok(SqliteStorage {
+            path: PathBuf::from(path),
+            conn: FakeDebug(conn),
+        })
+    }
+
+    /// Opens an existing SQLite database without changing it.
+    pub fn open_read_only(path: &Path) -> Result<Self, Error> {
+
+        let conn = rusqlite::Connection::open_with_flags(path, rusqlite::SQLITE_OPEN_READ_ONLY)
+            .map_err(|e| sqlite_error(path, e))?;
+        check_sqlite_version(path, &conn)?;
+
+        Ok(SqliteStorage {
+            path: PathBuf::from(path),
+            conn: FakeDebug(conn),
+        })
+    }
+}
+
+fn check_sqlite_version(path: &Path, conn: &rusqlite::Connection) -> Result<(), Error> {
+
+    let version: i64 = conn.query_row("PRAGMA user_version", &[], |row| row.get(0))
+        .map_err(|e| sqlite_error(path, e))?;
+
+    if SQLITE_SCHEMA_VERSION < version {
+        return Err(
+            Error::new(format!(
+                "Database was written by a newer version of {} (path: {:?}, database version: {}, supported \
+                 version: {}); please upgrade {}",
+                env!("CARGO_PKG_NAME"),
+                path,
+                version,
+                SQLITE_SCHEMA_VERSION,
+                env!("CARGO_PKG_NAME")
+            )).into_error(),
+        );
+    }
+
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn kind(&self) -> StorageKind {
+        StorageKind::Sqlite
+    }
+
+    fn load_raw(&mut self) -> Result<HashMap<String, serde_json::Value>, Error> {
+
+        // Each feed deserializes from its row's JSON with its items spliced
+        // in, in the form `[id, item]`.
+
+        let path = &self.path;
+        let mut items = HashMap::new();
+
+        {
+            let mut stmt = self.conn
+                .prepare("SELECT feed_url, item_id, data FROM items ORDER BY rowid")
+                .map_err(|e| sqlite_error(path, e))?;
+            let rows = stmt.query_map(&[], |row| {
+                let feed_url: String = row.get(0);
+                let item_id: String = row.get(1);
+                let data: String = row.get(2);
+                (feed_url, item_id, data)
+            }).map_err(|e| sqlite_error(path, e))?;
+            for row in rows {
+                let (feed_url, item_id, data) = row.map_err(|e| sqlite_error(path, e))?;
+                let item: serde_json::Value = serde_json::from_str(&data).map_err(|e| corrupt_error(path, e))?;
+                items
+                    .entry(feed_url)
+                    .or_insert_with(Vec::new)
+                    .push(serde_json::Value::Array(vec![serde_json::Value::String(item_id), item]));
+            }
+        }
+
+        let mut feeds = HashMap::new();
+
+        let mut stmt = self.conn.prepare("SELECT url, data FROM feeds").map_err(
+            |e| sqlite_error(path, e),
+        )?;
+        let rows = stmt.query_map(&[], |row| {
+            let url: String = row.get(0);
+            let data: String = row.get(1);
+            (url, data)
+        }).map_err(|e| sqlite_error(path, e))?;
+        for row in rows {
+            let (url, data) = row.map_err(|e| sqlite_error(path, e))?;
+            let mut raw: serde_json::Value = serde_json::from_str(&data).map_err(|e| corrupt_error(path, e))?;
+            if let Some(m) = raw.as_object_mut() {
+                m.insert(
+                    String::from("items"),
+                    serde_json::Value::Array(items.remove(&url).unwrap_or(Vec::new())),
+                );
+            }
+            feeds.insert(url, raw);
+        }
+
+        Ok(feeds)
+    }
+
+    fn record_item(&mut self, feed_url: &str, item_id: &str, item: &FeedItem) -> Result<(), Error> {
+
+        let data = serde_json::to_string(item).map_err(|e| {
+            Error::new(format!("Failed to encode feed item (feed URL: {}, item ID: {})", feed_url, item_id))
+                .with_cause(e)
+                .into_error()
+        })?;
+
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO items (feed_url, item_id, data) VALUES (?, ?, ?)",
+                &[&feed_url, &item_id, &data],
+            )
+            .map_err(|e| sqlite_error(&self.path, e))?;
+
+        Ok(())
+    }
+
+    fn commit(&mut self, feeds: &HashMap<String, Feed>) -> Result<(), Error> {
+
+        // Only rows that changed are written, and only rows for feeds and
+        // items that are gone are deleted. Items sent since the last commit
+        // were already written by `record_item` and so are left alone.
+
+        let path = &self.path;
+
+        let tx = self.conn.transaction().map_err(|e| sqlite_error(path, e))?;
+
+        {
+            let mut old_feeds = HashMap::new();
+            {
+                let mut stmt = tx.prepare("SELECT url, data FROM feeds").map_err(|e| sqlite_error(path, e))?;
+                let rows = stmt.query_map(&[], |row| {
+                    let url: String = row.get(0);
+                    let data: String = row.get(1);
+                    (url, data)
+                }).map_err(|e| sqlite_error(path, e))?;
+                for row in rows {
+                    let (url, data) = row.map_err(|e| sqlite_error(path, e))?;
+                    old_feeds.insert(url, data);
+                }
+            }
+
+            let mut old_items = HashMap::new();
+            {
+                let mut stmt = tx.prepare("SELECT feed_url, item_id, data FROM items")
+                    .map_err(|e| sqlite_error(path, e))?;
+                let rows = stmt.query_map(&[], |row| {
+                    let feed_url: String = row.get(0);
+                    let item_id: String = row.get(1);
+                    let data: String = row.get(2);
+                    ((feed_url, item_id), data)
+                }).map_err(|e| sqlite_error(path, e))?;
+                for row in rows {
+                    let (key, data) = row.map_err(|e| sqlite_error(path, e))?;
+                    old_items.insert(key, data);
+                }
+            }
+
+            let mut upsert_feed = tx.prepare("INSERT OR REPLACE INTO feeds (url, data) VALUES (?, ?)")
+                .map_err(|e| sqlite_error(path, e))?;
+            let mut upsert_item = tx.prepare("INSERT OR REPLACE INTO items (feed_url, item_id, data) VALUES (?, ?, ?)")
+                .map_err(|e| sqlite_error(path, e))?;
+
+            for (feed_url, feed) in feeds {
+
+                let mut raw = serde_json::to_value(feed).map_err(|e| {
+                    Error::new(format!("Failed to encode feed (feed URL: {})", feed_url))
+                        .with_cause(e)
+                        .into_error()
+                })?;
+
+                let items = match raw.as_object_mut().and_then(|m| m.remove("items")) {
+                    Some(serde_json::Value::Array(v)) => v,
+                    _ => Vec::new(),
+                };
+
+                let data = raw.to_string();
+                if old_feeds.remove(feed_url).as_ref() != Some(&data) {
+                    upsert_feed
+                        .execute(&[feed_url, &data])
+                        .map_err(|e| sqlite_error(path, e))?;
+                }
+
+                for item in items {
+                    let (item_id, item): (String, serde_json::Value) = serde_json::from_value(item).map_err(|e| {
+                        Error::new(format!("Failed to encode feed item (feed URL: {})", feed_url))
+                            .with_cause(e)
+                            .into_error()
+                    })?;
+                    let data = item.to_string();
+                    let key = (feed_url.clone(), item_id);
+                    if old_items.remove(&key).as_ref() != Some(&data) {
+                        upsert_item
+                            .execute(&[feed_url, &key.1, &data])
+                            .map_err(|e| sqlite_error(path, e))?;
+                    }
+                }
+            }
+
+            // Whatever is left over is gone.
+
+            let mut delete_feed = tx.prepare("DELETE FROM feeds WHERE url = ?")
+                .map_err(|e| sqlite_error(path, e))?;
+            for feed_url in old_feeds.keys() {
+                delete_feed.execute(&[feed_url]).map_err(|e| sqlite_error(path, e))?;
+            }
+
+            let mut delete_item = tx.prepare("DELETE FROM items WHERE feed_url = ? AND item_id = ?")
+                .map_err(|e| sqlite_error(path, e))?;
+            for &(ref feed_url, ref item_id) in old_items.keys() {
+                delete_item
+                    .execute(&[feed_url, item_id])
+                    .map_err(|e| sqlite_error(path, e))?;
+            }
+        }
+
+        tx.commit().map_err(|e| sqlite_error(path, e))
+    }
+}
+
+fn sqlite_error(path: &Path, e: rusqlite::Error) -> Error {
+    Error::new(format!("An SQLite database error occurred (path: {:?})", path))
+        .with_cause(e)
+        .into_error()
+}
+
+fn read_only_error(path: &Path) -> Error {
+    Error::new(format!("Database was opened read-only (path: {:?})", path)).into_error()
+}
+
+fn corrupt_error<E: Into<Box<std::error::Error>>>(path: &Path, e: E) -> Error {
+    Error::new(format!("Database is corrupt (path: {:?})", path))
+        .with_cause(e)
+        .into_error()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    const TEST_PATH_PREFIX: &str = "rss2email";
+
+    fn example_feeds(items: &str) -> HashMap<String, Feed> {
+        let feed = serde_json::from_str(&format!(r#"{{"title":"Example","items":[{}]}}"#, items)).unwrap();
+        vec![(String::from("http://example.com"), feed)].into_iter().collect()
+    }
+
+    #[test]
+    fn loading_a_version_0_database_upgrades_it() {
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let db_path = tdir.path().join("foo");
+        let v0 = r#"{"http://example.com":{"title":"Example","items":[["id alpha",{"last_observed":"2017-07-01T00:00:00Z"}]]}}"#;
+        std::fs::File::create(&db_path).unwrap().write_all(v0.as_bytes()).unwrap();
+        let mut storage = FileStorage::new(&db_path, FileFormat::Json);
+        let feeds = storage.load().unwrap();
+        assert_eq!(
+            feeds,
+            example_feeds(r#"["id alpha",{"last_observed":"2017-07-01T00:00:00Z"}]"#)
+        );
+        storage.commit(&feeds).unwrap();
+        let raw = serde_json::from_reader(std::fs::File::open(&db_path).unwrap()).unwrap();
+        assert_eq!(schema_version(&raw), Some(SCHEMA_VERSION));
+        assert!(sibling_path(&db_path, "v0.backup").exists());
+    }
+
+    #[test]
+    fn loading_a_newer_database_fails() {
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let db_path = tdir.path().join("foo");
+        let newer = format!(r#"{{"version":{},"feeds":{{}}}}"#, SCHEMA_VERSION + 1);
+        std::fs::File::create(&db_path).unwrap().write_all(newer.as_bytes()).unwrap();
+        let e = FileStorage::new(&db_path, FileFormat::Json).load().unwrap_err();
+        assert!(e.to_string().contains("newer version"));
+    }
+
+    #[test]
+    fn msgpack_storage_is_detected_on_open() {
+
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let db_path = tdir.path().join("foo");
+
+        let feeds = example_feeds(r#"["id alpha",{"last_observed":"2017-07-01T00:00:00Z"}]"#);
+        create(&db_path, StorageKind::MessagePack).unwrap().commit(&feeds).unwrap();
+
+        let mut storage = open(&db_path).unwrap();
+        assert_eq!(storage.kind(), StorageKind::MessagePack);
+        assert_eq!(storage.load().unwrap(), feeds);
+    }
+
+    #[test]
+    fn loading_read_only_storage_leaves_it_untouched() {
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let db_path = tdir.path().join("foo");
+        let v0 = r#"{"http://example.com":{"title":"Example","items":[]}}"#;
+        std::fs::File::create(&db_path).unwrap().write_all(v0.as_bytes()).unwrap();
+        let mut storage = open_read_only(&db_path).unwrap();
+        assert_eq!(storage.load().unwrap(), example_feeds(""));
+        storage.commit(&HashMap::new()).unwrap_err();
+        assert_eq!(std::fs::read_dir(tdir.path()).unwrap().count(), 1); // no "v0.backup" file
+    }
+
+    #[test]
+    fn sqlite_storage_keeps_committed_and_recorded_items() {
+
+        let tdir = TempDir::new(TEST_PATH_PREFIX).unwrap();
+        let db_path = tdir.path().join("foo");
+
+        let alpha = r#"["id alpha",{"last_observed":"2017-07-01T00:00:00Z"}]"#;
+        let bravo = r#"["id bravo",{"last_observed":"2017-07-02T00:00:00Z"}]"#;
+
+        {
+            let mut storage = create(&db_path, StorageKind::Sqlite).unwrap();
+            storage.commit(&example_feeds(alpha)).unwrap();
+            let item = serde_json::from_str(r#"{"last_observed":"2017-07-02T00:00:00Z"}"#).unwrap();
+            storage.record_item("http://example.com", "id bravo", &item).unwrap();
+        }
+
+        let mut storage = open(&db_path).unwrap();
+        assert_eq!(storage.kind(), StorageKind::Sqlite);
+        assert_eq!(
+            storage.load().unwrap(),
+            example_feeds(&format!("{},{}", alpha, bravo))
+        );
+
+        // Committing again deletes what's gone and keeps what isn't.
+
+        storage.commit(&example_feeds(bravo)).unwrap();
+        assert_eq!(storage.load().unwrap(), example_feeds(bravo));
+
+        storage.commit(&HashMap::new()).unwrap();
+        assert_eq!(storage.load().unwrap(), HashMap::new());
+    }
+}
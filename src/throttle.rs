@@ -0,0 +1,118 @@
+use std;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// `HostThrottle` limits how hard the fetcher threads may hit any one host.
+///
+/// At most `max_per_host` requests to a host may be in flight at once, and
+/// each request to a host starts at least `delay` after the previous one.
+#[derive(Clone, Debug)]
+pub struct HostThrottle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    max_per_host: usize,
+    delay: Duration,
+    hosts: Mutex<HashMap<String, HostState>>,
+    released: Condvar,
+}
+
+#[derive(Debug)]
+struct HostState {
+    in_flight: usize,
+    next_start: Instant,
+}
+
+/// `HostSlot` is permission to make a request to a host, held until dropped.
+#[derive(Debug)]
+pub struct HostSlot {
+    inner: Arc<Inner>,
+    host: String,
+}
+
+impl HostThrottle {
+    pub fn new(max_per_host: usize, delay: Duration) -> Self {
+        HostThrottle {
+            inner: Arc::new(Inner {
+                max_per_host: std::cmp::max(max_per_host, 1),
+                delay: delay,
+                hosts: Mutex::new(HashMap::new()),
+                released: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Blocks until a request to the host may start.
+    pub fn acquire(&self, host: &str) -> HostSlot {
+
+        let mut hosts = self.inner.hosts.lock().unwrap();
+
+        loop {
+            let now = Instant::now();
+
+            let wait = {
+                let state = hosts.entry(String::from(host)).or_insert_with(|| {
+                    HostState {
+                        in_flight: 0,
+                        next_start: now,
+                    }
+                });
+
+                if state.in_flight < self.inner.max_per_host && state.next_start <= now {
+                    state.in_flight += 1;
+                    state.next_start = now + self.inner.delay;
+                    break;
+                }
+
+                if state.in_flight < self.inner.max_per_host {
+                    Some(state.next_start - now)
+                } else {
+                    None // wait for a release
+                }
+            };
+
+            hosts = match wait {
+                None => self.inner.released.wait(hosts).unwrap(),
+                Some(x) => self.inner.released.wait_timeout(hosts, x).unwrap().0,
+            };
+        }
+
+        HostSlot {
+            inner: self.inner.clone(),
+            host: String::from(host),
+        }
+    }
+}
+
+impl Drop for HostSlot {
+    fn drop(&mut self) {
+        let mut hosts = self.inner.hosts.lock().unwrap();
+        if let Some(state) = hosts.get_mut(&self.host) {
+            state.in_flight -= 1;
+        }
+        self.inner.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_to_a_host_are_spaced_but_other_hosts_are_not_held_up() {
+
+        let delay = Duration::from_millis(200);
+        let throttle = HostThrottle::new(2, delay);
+
+        let start = Instant::now();
+        drop(throttle.acquire("example.com"));
+        let _other = throttle.acquire("example.org");
+        assert!(start.elapsed() < delay);
+
+        let _slot = throttle.acquire("example.com");
+        assert!(delay <= start.elapsed());
+    }
+}